@@ -0,0 +1,370 @@
+// Copyright 2023 developers of the `batmon` project
+// SPDX-License-Identifier: MPL-2.0
+
+//! Threshold actions
+use std::{fmt, io, process::ExitStatus, str::FromStr, time::Duration};
+
+use log::{info, warn};
+use serde::{de, Deserialize, Deserializer};
+use thiserror::Error;
+use tokio::{
+    process::Command,
+    task::JoinHandle,
+    time::{self, Instant},
+};
+#[cfg(feature = "dbus")]
+use zbus::{dbus_proxy, Connection};
+
+/// Grace period before power actions, so the user can plug in
+const POWER_GRACE: Duration = Duration::from_secs(60);
+
+#[cfg(feature = "dbus")]
+#[dbus_proxy(
+    interface = "org.freedesktop.login1.Manager",
+    default_service = "org.freedesktop.login1",
+    default_path = "/org/freedesktop/login1"
+)]
+trait Logind {
+    fn suspend(&self, interactive: bool) -> zbus::Result<()>;
+
+    fn hibernate(&self, interactive: bool) -> zbus::Result<()>;
+
+    fn hybrid_sleep(&self, interactive: bool) -> zbus::Result<()>;
+
+    fn power_off(&self, interactive: bool) -> zbus::Result<()>;
+}
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[cfg(feature = "dbus")]
+    #[error("logind error: {0}")]
+    Logind(#[from] zbus::Error),
+    #[cfg(not(feature = "dbus"))]
+    #[error("{0} requires batmon built with the dbus feature")]
+    Unsupported(Action),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("command failed: {0}")]
+    Command(ExitStatus),
+}
+
+/// Action run when a threshold is reached
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Suspend,
+    Hibernate,
+    HybridSleep,
+    PowerOff,
+    /// Command run through `sh -c`
+    Command(String),
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+#[error("unknown action {0:?}, expected suspend, hibernate, hybrid-sleep, poweroff or cmd:COMMAND")]
+pub struct UnknownAction(String);
+
+/// Power action names, or a command prefixed with `cmd:`
+impl FromStr for Action {
+    type Err = UnknownAction;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "suspend" => Self::Suspend,
+            "hibernate" => Self::Hibernate,
+            "hybrid-sleep" => Self::HybridSleep,
+            "poweroff" | "shutdown" => Self::PowerOff,
+            s => match s.strip_prefix("cmd:") {
+                Some(cmd) => Self::Command(cmd.trim_start().into()),
+                None => return Err(UnknownAction(s.into())),
+            },
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Action {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Named(String),
+            Command { command: String },
+        }
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Named(s) => s.parse().map_err(de::Error::custom),
+            Raw::Command { command } => Ok(Self::Command(command)),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Suspend => f.write_str("suspend"),
+            Self::Hibernate => f.write_str("hibernate"),
+            Self::HybridSleep => f.write_str("hybrid-sleep"),
+            Self::PowerOff => f.write_str("poweroff"),
+            Self::Command(cmd) => f.write_str(cmd),
+        }
+    }
+}
+
+impl Action {
+    /// Grace period used when the threshold doesn't specify one
+    /// commands run immediately
+    pub fn default_grace(&self) -> Duration {
+        match self {
+            Self::Command(_) => Duration::ZERO,
+            _ => POWER_GRACE,
+        }
+    }
+
+    /// Run the action, power actions go through logind on `bus`
+    /// or the system bus if unset
+    #[cfg(feature = "dbus")]
+    pub async fn run(&self, bus: Option<&Connection>) -> Result<(), Error> {
+        if let Self::Command(_) = self {
+            return self.run_command().await;
+        }
+        let system_bus;
+        let bus = match bus {
+            Some(bus) => bus,
+            None => {
+                system_bus = Connection::system().await?;
+                &system_bus
+            }
+        };
+        let logind = LogindProxy::new(bus).await?;
+        match self {
+            Self::Suspend => logind.suspend(false).await?,
+            Self::Hibernate => logind.hibernate(false).await?,
+            Self::HybridSleep => logind.hybrid_sleep(false).await?,
+            Self::PowerOff => logind.power_off(false).await?,
+            Self::Command(_) => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// Run the action, built without D-Bus only commands are supported
+    #[cfg(not(feature = "dbus"))]
+    pub async fn run(&self) -> Result<(), Error> {
+        match self {
+            Self::Command(_) => self.run_command().await,
+            _ => Err(Error::Unsupported(self.clone())),
+        }
+    }
+
+    /// Run a command action through `sh -c`
+    async fn run_command(&self) -> Result<(), Error> {
+        let Self::Command(cmd) = self else {
+            unreachable!("{self} isn't a command");
+        };
+        let status = Command::new("sh").arg("-c").arg(cmd).status().await?;
+        if !status.success() {
+            return Err(Error::Command(status));
+        }
+        Ok(())
+    }
+}
+
+/// Runs threshold actions once their grace period is over
+#[derive(Default)]
+pub(crate) struct ActionRunner {
+    /// Bus to reach logind on, the system bus if unset
+    #[cfg(feature = "dbus")]
+    bus: Option<Connection>,
+    /// Action waiting for its grace period, and the end of the grace period
+    pending: Option<(Instant, JoinHandle<()>)>,
+}
+
+impl ActionRunner {
+    #[cfg(all(test, feature = "dbus"))]
+    fn with_bus(bus: Connection) -> Self {
+        Self {
+            bus: Some(bus),
+            ..Default::default()
+        }
+    }
+
+    /// Schedule an action, replacing the pending one
+    /// actions without a grace period run immediately and can't be cancelled
+    pub fn schedule(&mut self, action: Action, grace: Duration) {
+        if !grace.is_zero() {
+            info!("running action: {action} in {}s", grace.as_secs());
+        }
+        #[cfg(feature = "dbus")]
+        let bus = self.bus.clone();
+        let task = async move {
+            time::sleep(grace).await;
+            info!("running action: {action}");
+            #[cfg(feature = "dbus")]
+            let res = action.run(bus.as_ref()).await;
+            #[cfg(not(feature = "dbus"))]
+            let res = action.run().await;
+            if let Err(e) = res {
+                warn!("action {action} failed: {e}");
+            }
+        };
+
+        if grace.is_zero() {
+            tokio::spawn(task);
+            return;
+        }
+        self.cancel();
+        self.pending = Some((Instant::now() + grace, tokio::spawn(task)));
+    }
+
+    /// Cancel the pending action, if any
+    /// an action past its grace period is already running and is left to finish
+    pub fn cancel(&mut self) {
+        let Some((due, pending)) = self.pending.take() else {
+            return;
+        };
+        if Instant::now() < due {
+            info!("cancelled pending action");
+            pending.abort();
+        }
+    }
+}
+
+/// Command action creating `path`
+#[cfg(test)]
+pub(crate) fn marker_action(path: &std::path::Path) -> Action {
+    Action::Command(format!("touch '{}'", path.display()))
+}
+
+/// Whether `path` was created, giving the command up to a second of real time
+/// as tests pause the clock
+#[cfg(test)]
+pub(crate) async fn marker_written(path: &std::path::Path) -> bool {
+    let path = path.to_owned();
+    tokio::task::spawn_blocking(move || {
+        (0..50).any(|_| {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            path.exists()
+        })
+    })
+    .await
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_actions() {
+        assert_eq!("suspend".parse(), Ok(Action::Suspend));
+        assert_eq!("hybrid-sleep".parse(), Ok(Action::HybridSleep));
+        assert_eq!("shutdown".parse(), Ok(Action::PowerOff));
+        assert_eq!(
+            "cmd: systemctl suspend".parse(),
+            Ok(Action::Command("systemctl suspend".into()))
+        );
+        assert!("hiberate".parse::<Action>().is_err());
+        assert!("systemctl suspend".parse::<Action>().is_err());
+    }
+
+    #[tokio::test]
+    async fn failing_command_is_reported() {
+        let res = Action::Command("exit 3".into()).run_command().await;
+        assert!(matches!(res, Err(Error::Command(status)) if status.code() == Some(3)));
+        Action::Command("true".into()).run_command().await.unwrap();
+    }
+
+    #[cfg(feature = "dbus")]
+    mod logind {
+        use std::{
+            os::unix::net::UnixStream,
+            sync::{Arc, Mutex},
+        };
+
+        use zbus::{dbus_interface, ConnectionBuilder, Guid};
+
+        use super::*;
+
+        /// Mock logind, recording the requested actions
+        #[derive(Clone, Default)]
+        struct MockLogind {
+            calls: Arc<Mutex<Vec<&'static str>>>,
+        }
+
+        #[dbus_interface(name = "org.freedesktop.login1.Manager")]
+        impl MockLogind {
+            fn suspend(&self, _interactive: bool) {
+                self.calls.lock().unwrap().push("suspend");
+            }
+
+            fn hibernate(&self, _interactive: bool) {
+                self.calls.lock().unwrap().push("hibernate");
+            }
+
+            fn hybrid_sleep(&self, _interactive: bool) {
+                self.calls.lock().unwrap().push("hybrid-sleep");
+            }
+
+            fn power_off(&self, _interactive: bool) {
+                self.calls.lock().unwrap().push("poweroff");
+            }
+        }
+
+        /// Peer to peer bus with a mock logind on the other end
+        async fn mock_bus() -> (Connection, MockLogind, Connection) {
+            let (server, client) = UnixStream::pair().unwrap();
+            let logind = MockLogind::default();
+            let guid = Guid::generate();
+            let server = ConnectionBuilder::unix_stream(server)
+                .server(&guid)
+                .p2p()
+                .serve_at("/org/freedesktop/login1", logind.clone())
+                .unwrap()
+                .build();
+            let client = ConnectionBuilder::unix_stream(client).p2p().build();
+            let (server, client) = futures_lite::future::zip(server, client).await;
+
+            (client.unwrap(), logind, server.unwrap())
+        }
+
+        #[tokio::test]
+        async fn power_actions_call_logind() {
+            let (bus, logind, _server) = mock_bus().await;
+            for action in [
+                Action::Suspend,
+                Action::Hibernate,
+                Action::HybridSleep,
+                Action::PowerOff,
+            ] {
+                action.run(Some(&bus)).await.unwrap();
+            }
+            assert_eq!(
+                *logind.calls.lock().unwrap(),
+                ["suspend", "hibernate", "hybrid-sleep", "poweroff"]
+            );
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn pending_action_runs_after_grace() {
+            let (bus, logind, _server) = mock_bus().await;
+            let mut runner = ActionRunner::with_bus(bus);
+            runner.schedule(Action::Hibernate, Duration::from_secs(60));
+
+            time::sleep(Duration::from_secs(59)).await;
+            assert!(logind.calls.lock().unwrap().is_empty());
+            time::sleep(Duration::from_secs(2)).await;
+            runner.pending.take().unwrap().1.await.unwrap();
+            assert_eq!(*logind.calls.lock().unwrap(), ["hibernate"]);
+        }
+
+        #[tokio::test(start_paused = true)]
+        async fn pending_action_is_cancelled() {
+            let (bus, logind, _server) = mock_bus().await;
+            let mut runner = ActionRunner::with_bus(bus);
+            runner.schedule(Action::PowerOff, Duration::from_secs(60));
+
+            time::sleep(Duration::from_secs(30)).await;
+            runner.cancel();
+            time::sleep(Duration::from_secs(60)).await;
+            assert!(logind.calls.lock().unwrap().is_empty());
+        }
+    }
+}
@@ -0,0 +1,105 @@
+// Copyright 2023 developers of the `batmon` project
+// SPDX-License-Identifier: MPL-2.0
+
+//! Aggregation of multiple batteries into a single level
+use std::path::Path;
+
+use crate::batstream::{sysfs::read_num_attr, BatLvl, BatName};
+
+struct AggBattery {
+    name: BatName,
+    /// Full energy of the battery in µWh
+    weight: u64,
+    lvl: Option<BatLvl>,
+}
+
+/// Energy weighted aggregate of the monitored batteries
+pub struct BatAggregate {
+    /// Combined name of all batteries, e.g `BAT0+BAT1`
+    name: BatName,
+    batteries: Vec<AggBattery>,
+}
+
+/// Full energy of a battery in µWh
+fn full_energy(root: &Path, battery: &str) -> Option<u64> {
+    if let Some(energy) = read_num_attr(root, battery, "energy_full") {
+        return Some(energy);
+    }
+    // charge (µAh) * voltage (µV) = 10^-12 Wh
+    let charge = read_num_attr(root, battery, "charge_full")?;
+    let voltage = read_num_attr(root, battery, "voltage_min_design")?;
+    Some(charge * voltage / 1_000_000)
+}
+
+impl BatAggregate {
+    pub fn new(root: &Path, batteries: &[BatName]) -> Self {
+        let energies: Option<Vec<_>> = batteries.iter().map(|b| full_energy(root, b)).collect();
+        // Without energy info for every battery, weigh them equally
+        let weights = energies
+            .filter(|e| e.iter().all(|&e| e != 0))
+            .unwrap_or_else(|| vec![1; batteries.len()]);
+
+        Self {
+            name: batteries.join("+").into(),
+            batteries: batteries
+                .iter()
+                .zip(weights)
+                .map(|(name, weight)| AggBattery {
+                    name: name.clone(),
+                    weight,
+                    lvl: None,
+                })
+                .collect(),
+        }
+    }
+
+    pub fn name(&self) -> &BatName {
+        &self.name
+    }
+
+    /// Update the level of a battery
+    /// returns the aggregated level, `None` until every battery has reported
+    pub fn update(&mut self, battery: &str, lvl: BatLvl) -> Option<BatLvl> {
+        self.batteries.iter_mut().find(|b| &*b.name == battery)?.lvl = Some(lvl);
+
+        let (mut energy, mut total) = (0, 0);
+        for b in &self.batteries {
+            energy += b.lvl?.get() as u64 * b.weight;
+            total += b.weight;
+        }
+
+        BatLvl::new((energy / total) as u8)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batstream::sysfs::FakeSysfs;
+
+    fn lvl(lvl: u8) -> BatLvl {
+        BatLvl::new(lvl).unwrap()
+    }
+
+    #[test]
+    fn levels_are_weighted_by_energy() {
+        let sysfs = FakeSysfs::empty();
+        sysfs.set("BAT0", "energy_full", "30000000");
+        sysfs.set("BAT1", "energy_full", "10000000");
+        let mut agg = BatAggregate::new(sysfs.root(), &["BAT0".into(), "BAT1".into()]);
+
+        assert_eq!(&**agg.name(), "BAT0+BAT1");
+        // Unknown until every battery has reported
+        assert_eq!(agg.update("BAT1", lvl(20)), None);
+        assert_eq!(agg.update("BAT0", lvl(60)), Some(lvl(50)));
+        assert_eq!(agg.update("BAT1", lvl(40)), Some(lvl(55)));
+        assert_eq!(agg.update("BAT2", lvl(0)), None);
+    }
+
+    #[test]
+    fn unknown_energy_weighs_equally() {
+        let mut agg = BatAggregate::new(Path::new(""), &["BAT0".into(), "BAT1".into()]);
+        assert_eq!(agg.update("BAT0", lvl(60)), None);
+        assert_eq!(agg.update("BAT1", lvl(20)), Some(lvl(40)));
+    }
+}
@@ -1,46 +1,137 @@
 // Copyright 2023 developers of the `batmon` project
 // SPDX-License-Identifier: MPL-2.0
 
+use std::path::PathBuf;
+
 use clap::{Parser, ValueEnum};
+use serde::Deserialize;
 
-use crate::batstream::BatLvl;
+use crate::{action::Action, batstream::BatLvl, notif::Sink, priority::ChargeLvl};
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[command(author, version, about, long_about = None)]
 pub struct Args {
-    /// The battery device to monitor
-    #[clap(short, long, default_value = "BAT0")]
-    pub battery: String,
+    /// The config file to use
+    /// [default: $XDG_CONFIG_HOME/batmon/config.toml]
+    #[clap(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Reload the config file when it changes,
+    /// it is always reloaded on SIGHUP
+    #[clap(long)]
+    pub watch_config: bool,
+
+    /// The battery devices to monitor, or "all".
+    /// Discovered from the power_supply class if omitted,
+    /// UPower's DisplayDevice with --backend upower
+    #[clap(short, long, value_delimiter = ',')]
+    pub battery: Vec<String>,
+
+    /// The adapter device to monitor.
+    /// Discovered from the power_supply class if omitted
+    #[clap(short, long)]
+    pub adapter: Option<String>,
+
+    /// The power supply class directory, e.g where /sys is bind-mounted
+    /// [default: /sys/class/power_supply]
+    #[clap(long, value_name = "DIR")]
+    pub sysfs_root: Option<PathBuf>,
+
+    /// The threshold for low battery [default: 30]
+    #[clap(long, value_name = "LEVEL")]
+    pub low: Option<BatLvl>,
+
+    /// The threshold for very low battery [default: 15]
+    #[clap(long, value_name = "LEVEL")]
+    pub very_low: Option<BatLvl>,
+
+    /// The threshold for critical battery [default: 8]
+    #[clap(long, value_name = "LEVEL")]
+    pub critical: Option<BatLvl>,
+
+    /// Repeat the critical battery notification at this interval,
+    /// or that of the most urgent custom threshold, until the adapter is connected
+    #[clap(long, value_name = "MINUTES", value_parser = clap::value_parser!(u64).range(1..))]
+    pub critical_reminder: Option<u64>,
 
-    /// The adapter device to monitor
-    #[clap(short, long, default_value = "ACAD")]
-    pub adapter: String,
+    /// Action to run when the battery reaches --action-level:
+    /// suspend, hibernate, hybrid-sleep, poweroff or cmd:COMMAND to run a shell command
+    #[clap(long)]
+    pub action: Option<Action>,
 
-    /// The threshold for low battery
-    #[clap(long, default_value = "30", value_name = "LEVEL")]
-    pub low: BatLvl,
+    /// The threshold for --action [default: 3]
+    #[clap(long, value_name = "LEVEL")]
+    pub action_level: Option<BatLvl>,
 
-    /// The threshold for very low battery
-    #[clap(long, default_value = "15", value_name = "LEVEL")]
-    pub very_low: BatLvl,
+    /// Seconds to wait before running --action,
+    /// cancelled if the adapter is connected [default: 60]
+    #[clap(long, value_name = "SECONDS")]
+    pub action_grace: Option<u64>,
 
-    /// The threshold for critical battery
-    #[clap(long, default_value = "8", value_name = "LEVEL")]
-    pub critical: BatLvl,
+    /// Notify when the battery charges to these levels, or "full",
+    /// while the adapter is connected
+    #[clap(long, value_name = "LEVEL", value_delimiter = ',')]
+    pub charged: Vec<ChargeLvl>,
 
-    /// The backend to use for fetching power data.
-    #[clap(long, default_value = "udev")]
-    pub backend: Backend,
+    /// How far the level must move back past a threshold
+    /// before it can be notified again [default: 2]
+    #[clap(long, value_name = "PERCENT")]
+    pub hysteresis: Option<BatLvl>,
+
+    /// Where notifications are sent [default: desktop]
+    #[clap(long, value_delimiter = ',', value_name = "SINK")]
+    pub notifier: Vec<Sink>,
+
+    /// The backend to use for fetching power data [default: auto]
+    #[clap(long)]
+    pub backend: Option<Backend>,
 
     /// The polling interval in seconds,
-    /// only applicable for --backend polling
-    #[clap(long, default_value = "5", value_name = "SECONDS")]
-    pub polling_interval: u64,
+    /// only applicable for --backend polling [default: 5]
+    #[clap(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub polling_interval: Option<u64>,
+
+    /// The polling interval in seconds of --backend hybrid [default: 60]
+    #[clap(long, value_name = "SECONDS", value_parser = clap::value_parser!(u64).range(1..))]
+    pub reconcile_interval: Option<u64>,
+
+    /// Record every battery event to a trace file
+    #[clap(long, value_name = "FILE")]
+    pub record: Option<PathBuf>,
+
+    /// The trace file replayed by --backend replay
+    #[clap(long, value_name = "FILE")]
+    pub replay: Option<PathBuf>,
+
+    /// How many times faster than recorded the trace is replayed [default: 1]
+    #[clap(long, value_name = "FACTOR", value_parser = positive)]
+    pub replay_speed: Option<f64>,
+}
+
+fn positive(s: &str) -> Result<f64, String> {
+    match s.parse() {
+        Ok(val) if val > 0.0 && f64::is_finite(val) => Ok(val),
+        _ => Err(format!("expected a number greater than 0, got {s:?}")),
+    }
 }
 
-#[derive(Clone, Copy, PartialEq, PartialOrd, Debug, ValueEnum)]
+#[derive(Clone, Copy, PartialEq, PartialOrd, Debug, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
 pub enum Backend {
+    /// Udev, then ACPI, then polling, switching on silence
+    Auto,
     Udev,
     Polling,
     Acpi,
+    /// Udev or ACPI events, reconciled with slow polling
+    Hybrid,
+    /// UPower D-Bus service
+    #[cfg(feature = "dbus")]
+    #[value(name = "upower")]
+    UPower,
+    /// Scripted battery profile, see the `simulate` config section
+    Simulate,
+    /// Trace recorded with --record, exits once replayed
+    Replay,
 }
@@ -91,7 +91,9 @@ impl<'a> TryFrom<&'a [u8]> for AcpiGenlEvent {
     type Error = DecodeError;
 
     fn try_from(value: &'a [u8]) -> Result<Self, Self::Error> {
-        let raw: &RawAcpiGenlEvent = bytemuck::from_bytes(value);
+        // Netlink attributes aren't necessarily aligned
+        let raw: RawAcpiGenlEvent = bytemuck::try_pod_read_unaligned(value)
+            .map_err(|_| DecodeError::from("invalid acpi_genl_event size"))?;
         let devc_bytes = must_cast_ref::<_, [u8; 20]>(&raw.device_class);
         let devc = CStr::from_bytes_until_nul(devc_bytes)
             .map_err(|_| DecodeError::from("device_class is not null terminated"))?
@@ -140,3 +142,43 @@ fn parse_nlas(buf: &[u8]) -> Result<Vec<AcpiGenlAttr>, DecodeError> {
         .map(|nla| nla.and_then(|n| AcpiGenlAttr::parse(&n)))
         .collect::<Result<Vec<_>, _>>()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Raw event, as sent by the kernel
+    fn raw_event(device_class: &[u8], kind: u32, data: u32) -> Vec<u8> {
+        let mut buf = vec![0; 20 + 15 + 1];
+        buf[..device_class.len()].copy_from_slice(device_class);
+        buf[20..28].copy_from_slice(b"ACPI0003");
+        buf.extend(kind.to_ne_bytes());
+        buf.extend(data.to_ne_bytes());
+        buf
+    }
+
+    #[test]
+    fn event_is_decoded() {
+        let buf = raw_event(b"ac_adapter", 0x80, 1);
+        let event = AcpiGenlEvent::try_from(&buf[..]).unwrap();
+        assert_eq!(event.device_class(), "ac_adapter");
+        assert_eq!((event.kind, event.data), (0x80, 1));
+
+        // From an unaligned buffer
+        let mut unaligned = vec![0];
+        unaligned.extend(&buf);
+        let event = AcpiGenlEvent::try_from(&unaligned[1..]).unwrap();
+        assert_eq!(event.device_class(), "ac_adapter");
+    }
+
+    #[test]
+    fn invalid_events() {
+        let buf = raw_event(b"battery", 0x80, 1);
+        assert!(AcpiGenlEvent::try_from(&buf[..10]).is_err());
+        // device_class fills the 20 bytes without a terminating nul
+        let buf = raw_event(&[b'a'; 20], 0x80, 1);
+        assert!(AcpiGenlEvent::try_from(&buf[..]).is_err());
+        let buf = raw_event(&[0xff, 0xfe], 0x80, 1);
+        assert!(AcpiGenlEvent::try_from(&buf[..]).is_err());
+    }
+}
@@ -5,6 +5,7 @@ use std::ops::{Deref, DerefMut};
 
 use futures_channel::oneshot;
 use futures_lite::StreamExt;
+use log::warn;
 use netlink_packet_core::{
     NetlinkHeader, NetlinkMessage, NetlinkPayload, NLM_F_ACK, NLM_F_REQUEST,
 };
@@ -22,9 +23,9 @@ use netlink_proto::{
     ConnectionHandle,
 };
 
-type Msg = GenlMessage<GenlCtrl>;
+use super::error::{Error, Result};
 
-pub type Result<T> = core::result::Result<T, netlink_proto::Error<Msg>>;
+type Msg = GenlMessage<GenlCtrl>;
 
 struct ConnGuard {
     handle: ConnectionHandle<Msg>,
@@ -33,11 +34,12 @@ struct ConnGuard {
 
 impl Drop for ConnGuard {
     fn drop(&mut self) {
-        self.cancel_tx
-            .take()
-            .unwrap()
-            .send(())
-            .expect("[BUG] nl connection died");
+        let Some(cancel_tx) = self.cancel_tx.take() else {
+            return;
+        };
+        if cancel_tx.send(()).is_err() {
+            warn!("netlink connection task exited before being cancelled");
+        }
     }
 }
 
@@ -55,7 +57,7 @@ impl DerefMut for ConnGuard {
     }
 }
 
-fn spawn_connection() -> Result<ConnGuard> {
+fn spawn_connection() -> std::io::Result<ConnGuard> {
     let (mut conn, handle, _) = new_connection(NETLINK_GENERIC)?;
     let (cancel_tx, mut cancel_rx) = oneshot::channel();
     tokio::spawn(async move {
@@ -118,8 +120,11 @@ pub async fn get_family_and_group() -> Result<(u16, u32)> {
     let mut family_id = None;
     let mut group_id = None;
     while let Some(msg) = req_rx.next().await {
-        let NetlinkPayload::InnerMessage(GenlMessage { payload: ctrl, .. }) = msg.payload else {
-            continue;
+        let ctrl = match msg.payload {
+            NetlinkPayload::InnerMessage(GenlMessage { payload: ctrl, .. }) => ctrl,
+            // ENOENT if the kernel lacks the family, e.g without CONFIG_ACPI
+            NetlinkPayload::Error(e) if e.code.is_some() => return Err(Error::Family(e.to_io())),
+            _ => continue,
         };
         let mut attr_iter = ctrl.nlas.into_iter();
         while family_id.is_none() || group_id.is_none() {
@@ -132,8 +137,5 @@ pub async fn get_family_and_group() -> Result<(u16, u32)> {
         }
     }
 
-    Ok((
-        family_id.expect("[BUG] unable to get family id"),
-        group_id.expect("[BUG] unable to get group id"),
-    ))
+    family_id.zip(group_id).ok_or(Error::MissingIds)
 }
@@ -12,6 +12,10 @@ pub enum Error {
     NetlinkCtrl(#[from] netlink_proto::Error<GenlMessage<GenlCtrl>>),
     #[error("netlink error: {0}")]
     NetlinkAcpi(#[from] netlink_proto::Error<Msg>),
+    #[error("failed to look up the acpi_event netlink family: {0}")]
+    Family(#[source] std::io::Error),
+    #[error("the acpi_event netlink family reply lacks its id or multicast group")]
+    MissingIds,
     #[error(transparent)]
     Io(#[from] std::io::Error),
 }
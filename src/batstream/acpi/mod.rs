@@ -5,6 +5,7 @@
 mod error;
 
 use std::{
+    collections::VecDeque,
     iter::FilterMap,
     path::Path,
     pin::Pin,
@@ -26,7 +27,10 @@ use self::{
     acpi_ids::get_family_and_group,
 };
 
-use super::{sysfs::BAT_BASE_PATH, udev_bat::extract_battery_cap, AdapterStatus, BatEvent};
+use super::{
+    udev_bat::{extract_adapter_status, extract_battery_cap, extract_battery_info},
+    AdapterStatus, BatEvent, BatName,
+};
 pub use error::*;
 mod acpi_event;
 mod acpi_ids;
@@ -45,39 +49,67 @@ type EvBuf = FilterMap<vec::IntoIter<AcpiGenlAttr>, fn(AcpiGenlAttr) -> Option<A
 pub struct AcpiStream {
     family_id: u16,
     netlink: NetlinkFramed<Msg, TokioSocket, NetlinkCodec>,
-    battery: Device,
+    batteries: Vec<(BatName, Device)>,
     buf: Option<EvBuf>,
+    /// The current readings, then the battery events of the last battery ACPI event
+    pending: VecDeque<crate::error::Result<BatEvent>>,
 }
 
 impl AcpiStream {
-    pub async fn new(battery_device: &str) -> Result<Self> {
-        let battery_path = Path::new(BAT_BASE_PATH).join(battery_device);
+    /// `root` is the power supply class directory, udev requires it to be under `/sys`
+    ///
+    /// The current readings are streamed first, starting with the adapter
+    pub async fn new(
+        root: &Path,
+        battery_devices: &[BatName],
+        adapter_device: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let adapter = Device::from_syspath(&root.join(adapter_device))?;
+        let batteries: Vec<_> = battery_devices
+            .iter()
+            .map(|name| {
+                let battery_path = root.join(&**name);
+                Ok((name.clone(), Device::from_syspath(&battery_path)?))
+            })
+            .collect::<Result<_>>()?;
+        let mut pending = VecDeque::with_capacity(1 + batteries.len() * 2);
+        pending.push_back(extract_adapter_status(&adapter).map(BatEvent::Adapter));
+        refresh_batteries(&batteries, &mut pending);
         let (family_id, group_id) = get_family_and_group().await?;
         let mut socket = TokioSocket::new(NETLINK_GENERIC)?;
         let inner_socket = socket.socket_mut();
         let addr = SocketAddr::new(0, group_bitmap(group_id));
-        inner_socket.bind(&addr).unwrap();
-        inner_socket.set_non_blocking(true).unwrap();
+        inner_socket.bind(&addr)?;
+        inner_socket.set_non_blocking(true)?;
 
         Ok(Self {
             family_id,
             netlink: NetlinkFramed::new(socket),
-            battery: Device::from_syspath(&battery_path)?,
+            batteries,
             buf: None,
+            pending,
         })
     }
 
-    fn next_buf(&mut self) -> Option<BatEvent> {
+    fn next_buf(&mut self) -> Option<crate::error::Result<BatEvent>> {
+        if let Some(ev) = self.pending.pop_front() {
+            return Some(ev);
+        }
+
         while let Some(ev) = self.buf.as_mut()?.next() {
             match ev.device_class() {
                 "ac_adapter" => {
                     return if ev.data == 1 {
-                        Some(BatEvent::Adapter(AdapterStatus::Connected))
+                        Some(Ok(BatEvent::Adapter(AdapterStatus::Connected)))
                     } else {
-                        Some(BatEvent::Adapter(AdapterStatus::Disconnected))
+                        Some(Ok(BatEvent::Adapter(AdapterStatus::Disconnected)))
                     }
                 }
-                "battery" => return Some(BatEvent::Battery(extract_battery_cap(&self.battery))),
+                "battery" => {
+                    // refresh every battery, unchanged ones are deduplicated downstream
+                    refresh_batteries(&self.batteries, &mut self.pending);
+                    return self.pending.pop_front();
+                }
                 _ => continue,
             }
         }
@@ -86,12 +118,28 @@ impl AcpiStream {
     }
 }
 
+/// Queue the level, then the details of every battery
+fn refresh_batteries(
+    batteries: &[(BatName, Device)],
+    pending: &mut VecDeque<crate::error::Result<BatEvent>>,
+) {
+    for (name, battery) in batteries {
+        pending.push_back(
+            extract_battery_cap(battery).map(|lvl| BatEvent::Battery(name.clone(), lvl)),
+        );
+        pending.push_back(Ok(BatEvent::Status(
+            name.clone(),
+            extract_battery_info(battery),
+        )));
+    }
+}
+
 impl Stream for AcpiStream {
-    type Item = Result<BatEvent>;
+    type Item = crate::error::Result<BatEvent>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         if let Some(ev) = self.next_buf() {
-            return Poll::Ready(Some(Ok(ev)));
+            return Poll::Ready(Some(ev));
         }
 
         loop {
@@ -119,7 +167,7 @@ impl Stream for AcpiStream {
             let Some(ev) = self.next_buf() else {
                 continue;
             };
-            return Poll::Ready(Some(Ok(ev)));
+            return Poll::Ready(Some(ev));
         }
     }
 }
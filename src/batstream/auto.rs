@@ -0,0 +1,283 @@
+// Copyright 2023 developers of the `batmon` project
+// SPDX-License-Identifier: MPL-2.0
+
+//! Backend picking the first working stream, falling back on silence
+use std::{
+    collections::HashMap,
+    future::Future,
+    path::{Path, PathBuf},
+    pin::Pin,
+    time::Duration,
+};
+
+use async_stream::stream;
+use futures_lite::{Stream, StreamExt};
+use log::{info, warn};
+use tokio::time::{self, MissedTickBehavior};
+
+use super::{
+    acpi::AcpiStream,
+    polling::polling_stream,
+    sysfs::{parse_capacity, read_attr},
+    udev::UdevStream,
+    BatEvent, BatLvl, BatName, BatStream,
+};
+use crate::error::Result;
+
+/// How often the current backend is checked against sysfs
+const SILENCE_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Backends in order of preference
+#[derive(Clone, Copy, Debug)]
+enum Fallback {
+    Udev,
+    Acpi,
+    Polling,
+}
+
+const FALLBACKS: [Fallback; 3] = [Fallback::Udev, Fallback::Acpi, Fallback::Polling];
+
+type OpenFuture<'a> = Pin<Box<dyn Future<Output = Result<BatStream<'a>>> + 'a>>;
+
+/// Opens the backend of a fallback
+type Opener = for<'a> fn(Fallback, &'a Path, Duration, &'a [BatName], &'a str) -> OpenFuture<'a>;
+
+fn open<'a>(
+    fallback: Fallback,
+    root: &'a Path,
+    interval: Duration,
+    batteries: &'a [BatName],
+    adapter: &'a str,
+) -> OpenFuture<'a> {
+    Box::pin(async move {
+        Ok(match fallback {
+            Fallback::Udev => Box::pin(UdevStream::new(root, batteries, adapter)?) as BatStream,
+            Fallback::Acpi => Box::pin(AcpiStream::new(root, batteries, adapter).await?),
+            Fallback::Polling => {
+                Box::pin(polling_stream(root, interval, batteries, adapter).await?)
+            }
+        })
+    })
+}
+
+/// Whether sysfs reports a level the backend hasn't
+fn is_stale(root: &Path, batteries: &[BatName], reported: &HashMap<BatName, BatLvl>) -> bool {
+    batteries.iter().any(|name| {
+        read_attr(root, name, "capacity")
+            .ok()
+            .and_then(|raw| parse_capacity(name, raw.as_bytes()).ok())
+            .is_some_and(|current| reported.get(name) != Some(&current))
+    })
+}
+
+/// Use the first backend that can be opened, out of udev, ACPI and polling.
+/// If sysfs reports a level the backend hasn't for a whole check period,
+/// without any battery event in the meantime, switch to the next one.
+/// A backend staying quiet while the level doesn't change is kept
+pub fn auto_stream(
+    root: PathBuf,
+    interval: Duration,
+    batteries: Vec<BatName>,
+    adapter: String,
+) -> impl Stream<Item = Result<BatEvent>> {
+    fallback_stream(open, root, interval, batteries, adapter)
+}
+
+fn fallback_stream(
+    open: Opener,
+    root: PathBuf,
+    interval: Duration,
+    batteries: Vec<BatName>,
+    adapter: String,
+) -> impl Stream<Item = Result<BatEvent>> {
+    stream! {
+        for fallback in FALLBACKS {
+            let mut inner = match open(fallback, &root, interval, &batteries, &adapter).await {
+                Ok(inner) => inner,
+                Err(e) if matches!(fallback, Fallback::Polling) => {
+                    yield Err(e);
+                    return;
+                }
+                Err(e) => {
+                    warn!("{fallback:?} backend unavailable: {e}");
+                    continue;
+                }
+            };
+            info!("using the {fallback:?} backend");
+
+            let mut reported = HashMap::new();
+            let mut active = false;
+            // Stale at the last check, a reading may have been on its way
+            let mut was_stale = false;
+            let mut check = time::interval(SILENCE_TIMEOUT);
+            check.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            check.tick().await;
+            loop {
+                tokio::select! {
+                    ev = inner.next() => {
+                        let Some(ev) = ev else {
+                            return;
+                        };
+                        if let Ok(BatEvent::Battery(name, lvl)) = &ev {
+                            reported.insert(name.clone(), *lvl);
+                            active = true;
+                        }
+                        yield ev;
+                    }
+                    _ = check.tick() => {
+                        // Polling is the last resort
+                        if matches!(fallback, Fallback::Polling) {
+                            continue;
+                        }
+                        let stale = !active && is_stale(&root, &batteries, &reported);
+                        if stale && was_stale {
+                            warn!("{fallback:?} backend went silent, falling back");
+                            break;
+                        }
+                        was_stale = stale;
+                        active = false;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use futures_lite::stream;
+
+    use super::*;
+    use crate::batstream::sysfs::FakeSysfs;
+
+    fn summary(ev: Option<Result<BatEvent>>) -> String {
+        match ev.expect("stream ended").unwrap() {
+            BatEvent::Battery(name, lvl) => format!("{name}:{lvl}"),
+            BatEvent::Adapter(status) => format!("{status:?}"),
+            BatEvent::Status(name, info) => format!("{name}:{}", info.status),
+        }
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_polling_outside_sys() {
+        // Neither udev nor ACPI can open devices outside of /sys
+        let sysfs = FakeSysfs::discharging();
+        let stream = auto_stream(
+            sysfs.root().to_owned(),
+            Duration::from_millis(10),
+            vec!["BAT0".into()],
+            "AC".into(),
+        );
+        let mut stream = Box::pin(stream);
+        let first = time::timeout(Duration::from_secs(2), stream.next()).await;
+        assert_eq!(summary(first.unwrap()), "Disconnected");
+        assert_eq!(summary(stream.next().await), "BAT0:50");
+    }
+
+    /// Udev fails to open and ACPI goes silent after its first reading
+    fn silent_acpi<'a>(
+        fallback: Fallback,
+        root: &'a Path,
+        interval: Duration,
+        batteries: &'a [BatName],
+        adapter: &'a str,
+    ) -> OpenFuture<'a> {
+        match fallback {
+            Fallback::Udev => Box::pin(async { Err(io::Error::other("no udev").into()) }),
+            Fallback::Acpi => Box::pin(async move {
+                let lvl = BatLvl::new(50).unwrap();
+                let first = stream::once(Ok(BatEvent::Battery(batteries[0].clone(), lvl)));
+                Ok(Box::pin(first.chain(stream::pending())) as BatStream)
+            }),
+            Fallback::Polling => open(fallback, root, interval, batteries, adapter),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn silent_backend_is_replaced_once_stale() {
+        let sysfs = FakeSysfs::discharging();
+        let stream = fallback_stream(
+            silent_acpi,
+            sysfs.root().to_owned(),
+            Duration::from_secs(1),
+            vec!["BAT0".into()],
+            "AC".into(),
+        );
+        let mut stream = Box::pin(stream);
+        let start = time::Instant::now();
+        assert_eq!(summary(stream.next().await), "BAT0:50");
+
+        // Silent but still matching sysfs for a whole period
+        let next = time::timeout(SILENCE_TIMEOUT * 3, stream.next()).await;
+        assert!(next.is_err());
+
+        sysfs.set("BAT0", "capacity", "40");
+        // Polling starts with the current readings
+        assert_eq!(summary(stream.next().await), "Disconnected");
+        assert_eq!(summary(stream.next().await), "BAT0:40");
+        assert_eq!(summary(stream.next().await), "BAT0:Discharging");
+        assert!(start.elapsed() <= SILENCE_TIMEOUT * 5);
+    }
+
+    /// Udev fails to open, and ACPI reports the new level a second after a check
+    fn late_acpi<'a>(
+        fallback: Fallback,
+        root: &'a Path,
+        interval: Duration,
+        batteries: &'a [BatName],
+        adapter: &'a str,
+    ) -> OpenFuture<'a> {
+        match fallback {
+            Fallback::Acpi => Box::pin(async move {
+                let name = batteries[0].clone();
+                let first = BatEvent::Battery(name.clone(), BatLvl::new(50).unwrap());
+                let late = async move {
+                    time::sleep(SILENCE_TIMEOUT + Duration::from_secs(1)).await;
+                    Ok(BatEvent::Battery(name, BatLvl::new(40).unwrap()))
+                };
+                let events = stream::once(Ok(first)).chain(stream::once_future(late));
+                Ok(Box::pin(events.chain(stream::pending())) as BatStream)
+            }),
+            _ => silent_acpi(fallback, root, interval, batteries, adapter),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn quiet_backend_is_kept() {
+        let sysfs = FakeSysfs::discharging();
+        let stream = fallback_stream(
+            late_acpi,
+            sysfs.root().to_owned(),
+            Duration::from_secs(1),
+            vec!["BAT0".into()],
+            "AC".into(),
+        );
+        let mut stream = Box::pin(stream);
+        assert_eq!(summary(stream.next().await), "BAT0:50");
+
+        // Changed a second before a check, and reported a second after it
+        time::sleep(SILENCE_TIMEOUT - Duration::from_secs(1)).await;
+        sysfs.set("BAT0", "capacity", "40");
+        assert_eq!(summary(stream.next().await), "BAT0:40");
+
+        // Quiet while the level doesn't change
+        let next = time::timeout(SILENCE_TIMEOUT * 5, stream.next()).await;
+        assert!(next.is_err());
+    }
+
+    #[test]
+    fn stale_levels() {
+        let sysfs = FakeSysfs::discharging();
+        let batteries = ["BAT0".into(), "BAT1".into()];
+        let mut reported = HashMap::new();
+        // Never reported
+        assert!(is_stale(sysfs.root(), &batteries, &reported));
+        reported.insert("BAT0".into(), BatLvl::new(50).unwrap());
+        // BAT1 can't be read
+        assert!(!is_stale(sysfs.root(), &batteries, &reported));
+        sysfs.set("BAT0", "capacity", "49");
+        assert!(is_stale(sysfs.root(), &batteries, &reported));
+    }
+}
@@ -0,0 +1,160 @@
+// Copyright 2023 developers of the `batmon` project
+// SPDX-License-Identifier: MPL-2.0
+
+//! Power supply discovery
+use std::{fs, io, path::Path};
+
+use super::sysfs::read_attr;
+
+/// Power supply type, as advertised by the `type` attribute
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SupplyType {
+    Battery,
+    Mains,
+    Usb,
+    Other,
+}
+
+impl From<&str> for SupplyType {
+    fn from(value: &str) -> Self {
+        match value {
+            "Battery" => Self::Battery,
+            "Mains" => Self::Mains,
+            "USB" => Self::Usb,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// Power supply scope, as advertised by the `scope` attribute
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SupplyScope {
+    /// Powers the whole system
+    System,
+    /// Powers a peripheral, e.g a wireless mouse
+    Device,
+}
+
+impl From<&str> for SupplyScope {
+    fn from(value: &str) -> Self {
+        match value {
+            "Device" => Self::Device,
+            _ => Self::System,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct PowerSupply {
+    pub name: String,
+    pub kind: SupplyType,
+    pub scope: SupplyScope,
+}
+
+/// list all power supplies, sorted by name
+pub fn power_supplies(root: &Path) -> io::Result<Vec<PowerSupply>> {
+    let mut supplies = Vec::new();
+    for entry in fs::read_dir(root)? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        let Ok(kind) = read_attr(root, &name, "type") else {
+            continue;
+        };
+        // scope is optional, supplies without it belong to the system
+        let scope = read_attr(root, &name, "scope").unwrap_or_default();
+        supplies.push(PowerSupply {
+            kind: kind.as_str().into(),
+            scope: scope.as_str().into(),
+            name,
+        });
+    }
+    supplies.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(supplies)
+}
+
+/// find the batteries powering the system
+pub fn system_batteries(root: &Path) -> io::Result<Vec<String>> {
+    let batteries: Vec<_> = power_supplies(root)?
+        .into_iter()
+        .filter(|s| s.kind == SupplyType::Battery && s.scope == SupplyScope::System)
+        .map(|s| s.name)
+        .collect();
+    if batteries.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no system battery found",
+        ));
+    }
+    Ok(batteries)
+}
+
+/// find the adapter powering the system
+/// mains adapters are preferred over USB ones
+pub fn system_adapter(root: &Path) -> io::Result<String> {
+    let mut adapters: Vec<_> = power_supplies(root)?
+        .into_iter()
+        .filter(|s| {
+            matches!(s.kind, SupplyType::Mains | SupplyType::Usb) && s.scope == SupplyScope::System
+        })
+        .collect();
+    adapters.sort_by_key(|s| s.kind != SupplyType::Mains);
+    adapters
+        .into_iter()
+        .next()
+        .map(|s| s.name)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no system adapter found"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batstream::sysfs::FakeSysfs;
+
+    /// A laptop with two batteries, a wireless mouse, and mains and USB-C adapters
+    fn laptop() -> FakeSysfs {
+        let sysfs = FakeSysfs::empty();
+        sysfs.set("BAT1", "type", "Battery");
+        sysfs.set("BAT0", "type", "Battery");
+        sysfs.set("BAT0", "scope", "System");
+        sysfs.set("hidpp_battery_0", "type", "Battery");
+        sysfs.set("hidpp_battery_0", "scope", "Device");
+        sysfs.set("AC", "type", "Mains");
+        sysfs.set("ucsi-source-psy-USBC000:001", "type", "USB");
+        // Not a power supply
+        sysfs.set("bogus", "online", "1");
+        sysfs
+    }
+
+    #[test]
+    fn system_batteries_skip_peripherals() {
+        let sysfs = laptop();
+        assert_eq!(system_batteries(sysfs.root()).unwrap(), ["BAT0", "BAT1"]);
+
+        let sysfs = FakeSysfs::empty();
+        sysfs.set("AC", "type", "Mains");
+        sysfs.set("hidpp_battery_0", "type", "Battery");
+        sysfs.set("hidpp_battery_0", "scope", "Device");
+        let err = system_batteries(sysfs.root()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn mains_adapters_are_preferred() {
+        let sysfs = laptop();
+        assert_eq!(system_adapter(sysfs.root()).unwrap(), "AC");
+
+        let sysfs = FakeSysfs::empty();
+        sysfs.set("BAT0", "type", "Battery");
+        sysfs.set("ucsi-source-psy-USBC000:001", "type", "USB");
+        sysfs.set("dock", "type", "Mains");
+        sysfs.set("dock", "scope", "Device");
+        assert_eq!(
+            system_adapter(sysfs.root()).unwrap(),
+            "ucsi-source-psy-USBC000:001"
+        );
+
+        let sysfs = FakeSysfs::empty();
+        sysfs.set("BAT0", "type", "Battery");
+        let err = system_adapter(sysfs.root()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+}
@@ -0,0 +1,88 @@
+// Copyright 2023 developers of the `batmon` project
+// SPDX-License-Identifier: MPL-2.0
+
+//! Event driven backend reconciled with slow polling
+use std::{path::Path, time::Duration};
+
+use futures_lite::{Stream, StreamExt};
+use log::warn;
+
+use super::{
+    acpi::AcpiStream, polling::polling_stream, udev::UdevStream, BatEvent, BatName, BatStream,
+    Dedup,
+};
+use crate::error::Result;
+
+/// Merge an event stream with a polling one, dropping identical readings
+pub fn merge<'a>(
+    events: impl Stream<Item = Result<BatEvent>> + 'a,
+    polling: impl Stream<Item = Result<BatEvent>> + 'a,
+) -> impl Stream<Item = Result<BatEvent>> + 'a {
+    let mut dedup = Dedup::default();
+    events.or(polling).filter(move |ev| match ev {
+        Ok(ev) => dedup.is_new(ev),
+        Err(_) => true,
+    })
+}
+
+/// Udev events, or ACPI events if udev is unavailable,
+/// reconciled with polling every `interval`
+pub async fn hybrid_stream<'a>(
+    root: &'a Path,
+    interval: Duration,
+    batteries: &'a [BatName],
+    adapter: &'a str,
+) -> Result<BatStream<'a>> {
+    let events: BatStream<'a> = match UdevStream::new(root, batteries, adapter) {
+        Ok(udev) => Box::pin(udev),
+        Err(e) => {
+            warn!("udev unavailable, using ACPI events: {e}");
+            Box::pin(AcpiStream::new(root, batteries, adapter).await?)
+        }
+    };
+    let polling = polling_stream(root, interval, batteries, adapter).await?;
+    Ok(Box::pin(merge(events, polling)))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::{future, stream};
+
+    use super::*;
+    use crate::batstream::{AdapterStatus, BatLvl};
+
+    fn bat(name: &str, lvl: u8) -> Result<BatEvent> {
+        Ok(BatEvent::Battery(name.into(), BatLvl::new(lvl).unwrap()))
+    }
+
+    fn summary(ev: &Result<BatEvent>) -> String {
+        match ev {
+            Ok(BatEvent::Battery(name, lvl)) => format!("{name}:{lvl}"),
+            Ok(BatEvent::Adapter(status)) => format!("{status:?}"),
+            Ok(BatEvent::Status(name, info)) => format!("{name}:{}", info.status),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn identical_readings_are_dropped() {
+        let events = stream::iter([
+            bat("BAT0", 50),
+            Ok(BatEvent::Adapter(AdapterStatus::Connected)),
+            bat("BAT0", 48),
+        ]);
+        // The event stream is polled first, it's exhausted before these
+        let polling = stream::iter([
+            bat("BAT0", 48),
+            bat("BAT1", 50),
+            Ok(BatEvent::Adapter(AdapterStatus::Connected)),
+            bat("BAT0", 47),
+        ]);
+        let merged: Vec<_> = future::block_on(merge(events, polling).collect());
+        let merged: Vec<_> = merged.iter().map(summary).collect();
+        assert_eq!(
+            merged,
+            ["BAT0:50", "Connected", "BAT0:48", "BAT1:50", "BAT0:47"]
+        );
+    }
+}
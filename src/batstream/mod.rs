@@ -3,23 +3,130 @@
 
 //! Battery Events Streams
 pub mod acpi;
+pub mod auto;
+pub mod discovery;
+pub mod hybrid;
 pub mod polling;
-mod sysfs;
+pub mod simulate;
+pub mod sysfs;
+pub mod trace;
 pub mod udev;
 mod udev_bat;
+#[cfg(feature = "dbus")]
+pub mod upower;
+
+use std::{collections::HashMap, fmt, pin::Pin, sync::Arc};
 
 use bounded_integer::BoundedU8;
+use futures_lite::Stream;
+
+use crate::error::Result;
 
 pub type BatLvl = BoundedU8<0, 100>;
 
+/// Battery device name, e.g `BAT0`
+pub type BatName = Arc<str>;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AdapterStatus {
     Connected,
     Disconnected,
 }
 
-#[derive(Debug, Clone, Copy)]
+/// Charging status, the sysfs `status` attribute
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[non_exhaustive]
+pub enum ChargeStatus {
+    Charging,
+    Discharging,
+    Full,
+    /// Plugged in but not charging, e.g because a charge threshold was reached
+    NotCharging,
+    #[default]
+    Unknown,
+}
+
+impl fmt::Display for ChargeStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Charging => "Charging",
+            Self::Discharging => "Discharging",
+            Self::Full => "Full",
+            Self::NotCharging => "Not charging",
+            Self::Unknown => "Unknown",
+        })
+    }
+}
+
+/// Battery details, `None` when not reported by the driver
+/// drivers report either energy or charge values
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct BatInfo {
+    pub status: ChargeStatus,
+    /// µWh
+    pub energy_now: Option<u64>,
+    pub energy_full: Option<u64>,
+    pub energy_full_design: Option<u64>,
+    /// µAh
+    pub charge_now: Option<u64>,
+    pub charge_full: Option<u64>,
+    pub charge_full_design: Option<u64>,
+    /// µV
+    pub voltage: Option<u64>,
+    /// µW, whether charging or discharging
+    pub power: Option<u64>,
+    /// µA, whether charging or discharging
+    pub current: Option<u64>,
+    pub cycle_count: Option<u64>,
+}
+
+impl BatInfo {
+    /// Full capacity relative to the design capacity, in percent
+    pub fn health(&self) -> Option<u8> {
+        let (full, design) = match (self.energy_full, self.energy_full_design) {
+            (Some(full), Some(design)) => (full, design),
+            _ => (self.charge_full?, self.charge_full_design?),
+        };
+        (design != 0).then(|| (full * 100 / design).min(100) as u8)
+    }
+
+    /// Power draw, from the current if the driver doesn't report it
+    pub fn power(&self) -> Option<u64> {
+        self.power
+            .or_else(|| Some(self.current? * self.voltage? / 1_000_000))
+    }
+}
+
+#[derive(Debug, Clone)]
+#[non_exhaustive]
 pub enum BatEvent {
     Adapter(AdapterStatus),
-    Battery(BatLvl),
+    Battery(BatName, BatLvl),
+    /// Sent along battery readings, and when the charging status changes
+    Status(BatName, BatInfo),
+}
+
+/// Last reading of every device, to drop repeated ones
+#[derive(Default)]
+pub(crate) struct Dedup {
+    adapter: Option<AdapterStatus>,
+    batteries: HashMap<BatName, BatLvl>,
+    statuses: HashMap<BatName, BatInfo>,
+}
+
+impl Dedup {
+    /// Whether the event differs from the last reading of its device
+    pub(crate) fn is_new(&mut self, event: &BatEvent) -> bool {
+        match event {
+            BatEvent::Adapter(status) => self.adapter.replace(*status) != Some(*status),
+            BatEvent::Battery(name, lvl) => self.batteries.insert(name.clone(), *lvl) != Some(*lvl),
+            BatEvent::Status(name, info) => {
+                self.statuses.insert(name.clone(), info.clone()).as_ref() != Some(info)
+            }
+        }
+    }
 }
+
+/// Type erased battery event stream, so backends can be swapped
+pub type BatStream<'a> = Pin<Box<dyn Stream<Item = Result<BatEvent>> + 'a>>;
@@ -2,7 +2,7 @@
 // SPDX-License-Identifier: MPL-2.0
 
 //! File Polling utilities
-use std::time::Duration;
+use std::{io, time::Duration};
 
 use async_stream::stream;
 use futures_lite::Stream;
@@ -12,23 +12,30 @@ use tokio::{
     time,
 };
 
-pub async fn read_file(file: &mut File) -> Vec<u8> {
+pub async fn read_file(file: &mut File) -> io::Result<Vec<u8>> {
     let mut out = Vec::new();
     out.reserve_exact(6);
-    file.read_to_end(&mut out).await.expect("failed to read fd");
-    file.rewind().await.expect("failed to rewind fd");
-    out
+    file.read_to_end(&mut out).await?;
+    file.rewind().await?;
+    Ok(out)
 }
 
-pub fn file_poller_cache(interval: Duration, mut file: File) -> impl Stream<Item = Vec<u8>> {
+/// Poll `file` every `interval`, yielding its content when it differs from `cache`
+pub fn file_poller_cache(
+    interval: Duration,
+    mut file: File,
+    mut cache: Vec<u8>,
+) -> impl Stream<Item = io::Result<Vec<u8>>> {
     stream! {
-        let mut cache = Vec::new();
         loop {
             time::sleep(interval).await;
-            let out = read_file(&mut file).await;
-            if cache != out {
-                cache = out.clone();
-                yield out;
+            match read_file(&mut file).await {
+                Ok(out) if cache != out => {
+                    cache = out.clone();
+                    yield Ok(out);
+                }
+                Ok(_) => {}
+                Err(e) => yield Err(e),
             }
         }
     }
@@ -4,115 +4,251 @@
 //! Polling based stream implementation
 mod file_poll;
 use std::{
-    ffi::OsStr,
+    collections::{HashMap, VecDeque},
     io,
-    os::unix::prelude::OsStrExt,
-    path::Path,
+    path::{Path, PathBuf},
     pin::Pin,
     task::{Context, Poll},
     time::Duration,
 };
 
-use futures_lite::{ready, stream::Skip, Stream, StreamExt};
+use futures_lite::{ready, Stream};
 use pin_project_lite::pin_project;
 use tokio::fs::File;
 
-use self::file_poll::file_poller_cache;
+use self::file_poll::{file_poller_cache, read_file};
 
-use super::{sysfs::BAT_BASE_PATH, AdapterStatus, BatEvent, BatLvl};
+use super::{
+    sysfs::{parse_capacity, parse_online, read_info},
+    AdapterStatus, BatEvent, BatInfo, BatName,
+};
+use crate::error::Result;
 
 pin_project! {
+    #[project = PollingStreamProj]
     pub struct PollingStream<FilePollS> {
+        battery_states: Vec<(BatName, Pin<Box<FilePollS>>)>,
+        // `status` of the batteries reporting it
+        status_states: Vec<(BatName, Pin<Box<FilePollS>>)>,
+        // The initial readings, then the details following a level reading
+        pending: VecDeque<Result<BatEvent>>,
+        // Last streamed details, a status file rewrite may not change them
+        statuses: HashMap<BatName, BatInfo>,
+        // For reading the battery details
+        root: PathBuf,
+        adapter_name: String,
+        // Last streamed adapter status
+        adapter: Option<AdapterStatus>,
         #[pin]
-        battery_state: FilePollS,
-        #[pin]
-        adapter_state: Skip<FilePollS>,
+        adapter_state: FilePollS,
     }
 }
 
 pub async fn polling_stream(
+    root: &Path,
     interval: Duration,
-    battery_device: impl AsRef<Path>,
+    battery_devices: &[BatName],
     adapter_device: impl AsRef<Path>,
-) -> io::Result<PollingStream<impl Stream<Item = Vec<u8>>>> {
-    PollingStream::new(interval, battery_device, adapter_device, file_poller_cache).await
+) -> io::Result<PollingStream<impl Stream<Item = io::Result<Vec<u8>>>>> {
+    PollingStream::new(
+        root,
+        interval,
+        battery_devices,
+        adapter_device,
+        file_poller_cache,
+    )
+    .await
 }
 
 impl<S: Stream> PollingStream<S> {
+    /// `root` is the power supply class directory, usually
+    /// [`BAT_BASE_PATH`](super::sysfs::BAT_BASE_PATH)
+    ///
+    /// The current readings are streamed first, starting with the adapter,
+    /// `stream_gen` then only yields the changes from the reading it's given
     pub async fn new(
+        root: &Path,
         interval: Duration,
-        battery_device: impl AsRef<Path>,
+        battery_devices: &[BatName],
         adapter_device: impl AsRef<Path>,
-        stream_gen: fn(Duration, File) -> S,
+        stream_gen: fn(Duration, File, Vec<u8>) -> S,
     ) -> io::Result<Self> {
-        let mut battery_path = Path::new(BAT_BASE_PATH).join(battery_device);
-        battery_path.push("capacity");
-        let mut adapter_path = Path::new(BAT_BASE_PATH).join(adapter_device);
-        adapter_path.push("online");
-
-        let battery = File::open(battery_path).await?;
-        let adapter = File::open(adapter_path).await?;
+        let adapter_device = adapter_device.as_ref();
+        let adapter_name = adapter_device.to_string_lossy();
+        let mut adapter = File::open(root.join(adapter_device).join("online")).await?;
+        let online = read_file(&mut adapter).await?;
+        let mut pending = VecDeque::with_capacity(1 + battery_devices.len() * 2);
+        pending.push_back(parse_online(&adapter_name, &online).map(BatEvent::Adapter));
+
+        let mut battery_states = Vec::with_capacity(battery_devices.len());
+        let mut status_states = Vec::with_capacity(battery_devices.len());
+        for name in battery_devices {
+            let battery_path = root.join(&**name);
+            let mut battery = File::open(battery_path.join("capacity")).await?;
+            let capacity = read_file(&mut battery).await?;
+            pending.push_back(
+                parse_capacity(name, &capacity).map(|lvl| BatEvent::Battery(name.clone(), lvl)),
+            );
+            pending.push_back(Ok(BatEvent::Status(name.clone(), read_info(root, name))));
+            battery_states.push((
+                name.clone(),
+                Box::pin(stream_gen(interval, battery, capacity)),
+            ));
+            if let Ok(mut status) = File::open(battery_path.join("status")).await {
+                let raw = read_file(&mut status).await?;
+                status_states.push((name.clone(), Box::pin(stream_gen(interval, status, raw))));
+            }
+        }
 
         Ok(Self {
-            battery_state: stream_gen(interval, battery),
-            adapter_state: stream_gen(interval, adapter).skip(1),
+            battery_states,
+            status_states,
+            pending,
+            statuses: HashMap::new(),
+            root: root.into(),
+            adapter_name: adapter_name.into(),
+            adapter: None,
+            adapter_state: stream_gen(interval, adapter, online),
         })
     }
 }
 
-fn parse_os_str(raw: &[u8]) -> &str {
-    OsStr::from_bytes(raw)
-        .to_str()
-        .and_then(|s| s.strip_suffix('\n'))
-        .expect("data is not valid utf8?!")
-}
-
-fn parse_battery(raw: Vec<u8>) -> BatLvl {
-    parse_os_str(&raw)
-        .parse()
-        .expect("battery capacity is not a valid?!")
-}
-
-fn parse_adapter(raw: Vec<u8>) -> AdapterStatus {
-    let dat = parse_os_str(&raw);
-    if dat == "1" {
-        AdapterStatus::Connected
-    } else {
-        AdapterStatus::Disconnected
-    }
-}
-
-fn handle_item<T: Stream<Item = Vec<u8>>, R>(
+fn handle_item<T: Stream<Item = io::Result<Vec<u8>>>, R>(
     stream: Pin<&mut T>,
     cx: &mut Context<'_>,
-    parser: impl FnOnce(Vec<u8>) -> R,
-) -> Poll<Option<R>> {
+    parser: impl FnOnce(&[u8]) -> Result<R>,
+) -> Poll<Option<Result<R>>> {
     let item = ready!(stream.poll_next(cx));
     let Some(item) = item else {
         return Poll::Ready(None);
     };
 
-    Poll::Ready(Some(parser(item)))
+    Poll::Ready(Some(item.map_err(Into::into).and_then(|raw| parser(&raw))))
 }
 
-impl<S: Stream<Item = Vec<u8>>> Stream for PollingStream<S> {
-    type Item = io::Result<BatEvent>;
+impl<S: Stream<Item = io::Result<Vec<u8>>>> Stream for PollingStream<S> {
+    type Item = Result<BatEvent>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let this = self.project();
+        let mut this = self.project();
+
+        // Readings identical to the last streamed one are skipped,
+        // and their stream polled again
+        loop {
+            let ev = match this.pending.pop_front() {
+                Some(ev) => ev,
+                None => match poll_devices(&mut this, cx) {
+                    Poll::Ready(Some(ev)) => ev,
+                    other => return other,
+                },
+            };
+            match &ev {
+                Ok(BatEvent::Adapter(status)) => {
+                    if this.adapter.replace(*status) == Some(*status) {
+                        continue;
+                    }
+                }
+                Ok(BatEvent::Status(name, info)) => {
+                    if this.statuses.insert(name.clone(), info.clone()).as_ref() == Some(info) {
+                        continue;
+                    }
+                }
+                Ok(BatEvent::Battery(..)) | Err(_) => {}
+            }
+            return Poll::Ready(Some(ev));
+        }
+    }
+}
+
+/// The next reading of the adapter, then the batteries
+fn poll_devices<S: Stream<Item = io::Result<Vec<u8>>>>(
+    this: &mut PollingStreamProj<'_, S>,
+    cx: &mut Context<'_>,
+) -> Poll<Option<Result<BatEvent>>> {
+    let adapter_name = &**this.adapter_name;
+    if let Poll::Ready(adp) = handle_item(this.adapter_state.as_mut(), cx, |raw| {
+        parse_online(adapter_name, raw).map(BatEvent::Adapter)
+    }) {
+        return Poll::Ready(adp);
+    }
 
-        if let Poll::Ready(adp) = handle_item(this.adapter_state, cx, |v| {
-            BatEvent::Adapter(parse_adapter(v))
+    for (name, battery_state) in this.battery_states.iter_mut() {
+        if let Poll::Ready(bat) = handle_item(battery_state.as_mut(), cx, |raw| {
+            parse_capacity(name, raw).map(|lvl| BatEvent::Battery(name.clone(), lvl))
         }) {
-            return Poll::Ready(Ok(adp).transpose());
+            if let Some(Ok(_)) = bat {
+                let info = read_info(this.root, name);
+                this.pending
+                    .push_back(Ok(BatEvent::Status(name.clone(), info)));
+            }
+            return Poll::Ready(bat);
         }
+    }
 
-        if let Poll::Ready(bat) = handle_item(this.battery_state, cx, |v| {
-            BatEvent::Battery(parse_battery(v))
+    // The status can change on its own, e.g once charging stops
+    for (name, status_state) in this.status_states.iter_mut() {
+        if let Poll::Ready(status) = handle_item(status_state.as_mut(), cx, |_| {
+            Ok(BatEvent::Status(name.clone(), read_info(this.root, name)))
         }) {
-            return Poll::Ready(Ok(bat).transpose());
+            return Poll::Ready(status);
         }
+    }
+
+    Poll::Pending
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::StreamExt;
+    use tokio::time;
+
+    use super::*;
+    use crate::batstream::sysfs::FakeSysfs;
+
+    async fn next(stream: &mut (impl Stream<Item = Result<BatEvent>> + Unpin)) -> String {
+        let ev = time::timeout(Duration::from_secs(2), stream.next())
+            .await
+            .expect("no event")
+            .unwrap();
+        match ev.unwrap() {
+            BatEvent::Battery(name, lvl) => format!("{name}:{lvl}"),
+            BatEvent::Adapter(status) => format!("{status:?}"),
+            BatEvent::Status(name, info) => {
+                format!("{name}:{}:{:?}", info.status, info.power())
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn fake_sysfs_changes_are_streamed() {
+        let sysfs = FakeSysfs::discharging();
+        let interval = Duration::from_millis(10);
+        let stream = polling_stream(sysfs.root(), interval, &["BAT0".into()], "AC").await;
+        let mut stream = Box::pin(stream.unwrap());
+
+        // The current readings, the adapter first
+        assert_eq!(next(&mut stream).await, "Disconnected");
+        assert_eq!(next(&mut stream).await, "BAT0:50");
+        assert_eq!(next(&mut stream).await, "BAT0:Discharging:Some(12000000)");
+
+        // The unchanged details aren't streamed again
+        sysfs.set("BAT0", "capacity", "49");
+        assert_eq!(next(&mut stream).await, "BAT0:49");
+
+        // The details are read once the status changes
+        sysfs.set("BAT0", "power_now", "0");
+        sysfs.set("BAT0", "status", "Not charging");
+        assert_eq!(next(&mut stream).await, "BAT0:Not charging:Some(0)");
+
+        sysfs.set("AC", "online", "1");
+        assert_eq!(next(&mut stream).await, "Connected");
+    }
 
-        Poll::Pending
+    #[tokio::test]
+    async fn missing_device_fails_to_open() {
+        let sysfs = FakeSysfs::discharging();
+        let interval = Duration::from_millis(10);
+        let res = polling_stream(sysfs.root(), interval, &["BAT1".into()], "AC").await;
+        assert_eq!(res.err().map(|e| e.kind()), Some(io::ErrorKind::NotFound));
     }
 }
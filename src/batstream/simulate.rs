@@ -0,0 +1,228 @@
+// Copyright 2023 developers of the `batmon` project
+// SPDX-License-Identifier: MPL-2.0
+
+//! Simulated battery replaying a scripted profile, for demos and tests
+use std::time::Duration;
+
+use async_stream::stream;
+use futures_lite::Stream;
+use tokio::time;
+
+use super::{AdapterStatus, BatEvent, BatInfo, BatLvl, BatName, ChargeStatus};
+use crate::error::Result;
+
+/// Part of a simulation with a constant adapter status and rate
+#[derive(Debug, Clone, PartialEq)]
+pub struct Phase {
+    /// Simulated duration
+    pub duration: Duration,
+    pub adapter: AdapterStatus,
+    /// Level change in percent per simulated minute, negative while discharging
+    pub rate: f64,
+}
+
+/// Scripted battery profile
+#[derive(Debug, Clone, PartialEq)]
+pub struct Profile {
+    /// Starting level in percent
+    pub level: f64,
+    /// How much faster than real time the simulation runs
+    pub speed: f64,
+    /// Simulated time between readings
+    pub interval: Duration,
+    /// Readings deviate randomly by up to this many percent
+    pub noise: u8,
+    /// Seed of the noise, the same seed replays the same readings
+    pub seed: u64,
+    pub phases: Vec<Phase>,
+}
+
+impl Default for Profile {
+    /// Discharge to 5% in 45 minutes, then charge for 30 minutes
+    fn default() -> Self {
+        Self {
+            level: 50.0,
+            speed: 60.0,
+            interval: Duration::from_secs(30),
+            noise: 0,
+            seed: 0,
+            phases: vec![
+                Phase {
+                    duration: Duration::from_secs(45 * 60),
+                    adapter: AdapterStatus::Disconnected,
+                    rate: -1.0,
+                },
+                Phase {
+                    duration: Duration::from_secs(30 * 60),
+                    adapter: AdapterStatus::Connected,
+                    rate: 2.0,
+                },
+            ],
+        }
+    }
+}
+
+/// xorshift64*, good enough for noise
+struct Noise {
+    state: u64,
+    max: u8,
+}
+
+impl Noise {
+    fn new(seed: u64, max: u8) -> Self {
+        // The state must not be 0
+        Self {
+            state: seed ^ 0x9E37_79B9_7F4A_7C15,
+            max,
+        }
+    }
+
+    /// Deviation in `-max..=max`
+    fn next(&mut self) -> i16 {
+        if self.max == 0 {
+            return 0;
+        }
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        let val = self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 32;
+        let span = 2 * u64::from(self.max) + 1;
+        (val % span) as i16 - i16::from(self.max)
+    }
+}
+
+fn status(adapter: AdapterStatus, level: f64, rate: f64) -> ChargeStatus {
+    match adapter {
+        AdapterStatus::Disconnected => ChargeStatus::Discharging,
+        AdapterStatus::Connected if level >= 100.0 => ChargeStatus::Full,
+        AdapterStatus::Connected if rate > 0.0 => ChargeStatus::Charging,
+        AdapterStatus::Connected => ChargeStatus::NotCharging,
+    }
+}
+
+/// Replay `profile` for every battery, then end
+/// each phase starts with its adapter status, then the readings follow every interval
+pub fn simulate_stream(
+    profile: Profile,
+    batteries: Vec<BatName>,
+) -> impl Stream<Item = Result<BatEvent>> {
+    stream! {
+        let mut noise = Noise::new(profile.seed, profile.noise);
+        let mut level = profile.level.clamp(0.0, 100.0);
+        let mut adapter = None;
+        let mut charge_status = None;
+        let tick = profile.interval.div_f64(profile.speed);
+        let minutes = profile.interval.as_secs_f64() / 60.0;
+        for phase in &profile.phases {
+            if adapter.replace(phase.adapter) != Some(phase.adapter) {
+                yield Ok(BatEvent::Adapter(phase.adapter));
+            }
+            let mut elapsed = Duration::ZERO;
+            loop {
+                let current = status(phase.adapter, level, phase.rate);
+                if charge_status.replace(current) != Some(current) {
+                    for name in &batteries {
+                        let info = BatInfo {
+                            status: current,
+                            ..Default::default()
+                        };
+                        yield Ok(BatEvent::Status(name.clone(), info));
+                    }
+                }
+                let reading = (level.round() as i16 + noise.next()).clamp(0, 100);
+                for name in &batteries {
+                    let lvl = BatLvl::new_saturating(reading as u8);
+                    yield Ok(BatEvent::Battery(name.clone(), lvl));
+                }
+
+                elapsed += profile.interval;
+                if elapsed > phase.duration {
+                    break;
+                }
+                time::sleep(tick).await;
+                level = (level + phase.rate * minutes).clamp(0.0, 100.0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::StreamExt;
+
+    use super::*;
+
+    fn summary(ev: Result<BatEvent>) -> String {
+        match ev.unwrap() {
+            BatEvent::Battery(name, lvl) => format!("{name}:{lvl}"),
+            BatEvent::Adapter(status) => format!("{status:?}"),
+            BatEvent::Status(name, info) => format!("{name}:{}", info.status),
+        }
+    }
+
+    fn profile(noise: u8) -> Profile {
+        Profile {
+            level: 10.0,
+            speed: 60.0,
+            interval: Duration::from_secs(60),
+            noise,
+            seed: 7,
+            phases: vec![
+                Phase {
+                    duration: Duration::from_secs(2 * 60),
+                    adapter: AdapterStatus::Disconnected,
+                    rate: -2.5,
+                },
+                Phase {
+                    duration: Duration::from_secs(60),
+                    adapter: AdapterStatus::Connected,
+                    rate: 1.0,
+                },
+            ],
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn profile_is_replayed() {
+        let start = time::Instant::now();
+        let events: Vec<_> = simulate_stream(profile(0), vec!["BAT0".into()])
+            .map(summary)
+            .collect()
+            .await;
+        assert_eq!(
+            events,
+            [
+                "Disconnected",
+                "BAT0:Discharging",
+                "BAT0:10",
+                "BAT0:8",
+                "BAT0:5",
+                "Connected",
+                "BAT0:Charging",
+                "BAT0:5",
+                "BAT0:6",
+            ]
+        );
+        // 3 simulated minutes, 60 times faster
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn noise_is_seeded() {
+        let levels = || async {
+            simulate_stream(profile(3), vec!["BAT0".into()])
+                .map(summary)
+                .collect::<Vec<_>>()
+                .await
+        };
+        let first = levels().await;
+        assert_eq!(first, levels().await);
+        assert_ne!(
+            first,
+            simulate_stream(profile(0), vec!["BAT0".into()])
+                .map(summary)
+                .collect::<Vec<_>>()
+                .await
+        );
+    }
+}
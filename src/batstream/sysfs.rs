@@ -1,10 +1,199 @@
 // Copyright 2023 developers of the `batmon` project
 // SPDX-License-Identifier: MPL-2.0
 
-//! SysFS constants
+//! SysFS constants and helpers
+use std::{fs, io, path::Path, str};
+
 use const_format::concatcp;
+use log::debug;
+
+use super::{AdapterStatus, BatInfo, BatLvl, ChargeStatus};
+use crate::error::{Error, Result};
 
 /// Battery Subsystem
 pub const BAT_SUBSYS: &str = "power_supply";
 /// Base path to Battery Subsystem class
 pub const BAT_BASE_PATH: &str = concatcp!("/sys/class/", BAT_SUBSYS);
+
+/// read a power supply attribute, without the trailing newline
+/// `root` is the power supply class directory, usually [`BAT_BASE_PATH`]
+pub fn read_attr(root: &Path, device: &str, attr: &str) -> io::Result<String> {
+    let mut val = fs::read_to_string(root.join(device).join(attr))?;
+    val.truncate(val.trim_end().len());
+    Ok(val)
+}
+
+/// read a numeric power supply attribute
+pub fn read_num_attr(root: &Path, device: &str, attr: &str) -> Option<u64> {
+    read_attr(root, device, attr).ok()?.parse().ok()
+}
+
+fn invalid(device: &str, property: &'static str, raw: &[u8]) -> Error {
+    Error::InvalidReading {
+        device: device.into(),
+        property,
+        value: String::from_utf8_lossy(raw).into(),
+    }
+}
+
+/// Parse a capacity reading,
+/// firmware reporting more than 100% is clamped
+pub fn parse_capacity(device: &str, raw: &[u8]) -> Result<BatLvl> {
+    let val = str::from_utf8(raw)
+        .ok()
+        .and_then(|s| s.trim_end().parse::<u64>().ok())
+        .ok_or_else(|| invalid(device, "capacity", raw))?;
+    if val > 100 {
+        debug!("{device} reported a capacity of {val}%, clamping it");
+    }
+    Ok(BatLvl::new_saturating(val.min(100) as u8))
+}
+
+/// Parse an adapter `online` reading,
+/// USB supplies report 2 when online with a programmable voltage
+pub fn parse_online(device: &str, raw: &[u8]) -> Result<AdapterStatus> {
+    let val = str::from_utf8(raw)
+        .ok()
+        .and_then(|s| s.trim_end().parse::<u8>().ok())
+        .ok_or_else(|| invalid(device, "online", raw))?;
+    Ok(if val == 0 {
+        AdapterStatus::Disconnected
+    } else {
+        AdapterStatus::Connected
+    })
+}
+
+/// Parse a `status` reading, unexpected values are `Unknown`
+pub fn parse_status(raw: &str) -> ChargeStatus {
+    match raw {
+        "Charging" => ChargeStatus::Charging,
+        "Discharging" => ChargeStatus::Discharging,
+        "Full" => ChargeStatus::Full,
+        "Not charging" => ChargeStatus::NotCharging,
+        _ => ChargeStatus::Unknown,
+    }
+}
+
+/// Parse battery details from its attributes, looked up by their sysfs name
+/// unreadable values are left out
+pub fn parse_info(attr: impl Fn(&str) -> Option<String>) -> BatInfo {
+    let num = |name| attr(name)?.parse().ok();
+    // Some drivers report a negative current while discharging
+    let abs = |name| attr(name)?.parse::<i64>().ok().map(i64::unsigned_abs);
+    BatInfo {
+        status: attr("status").map_or(ChargeStatus::Unknown, |s| parse_status(&s)),
+        energy_now: num("energy_now"),
+        energy_full: num("energy_full"),
+        energy_full_design: num("energy_full_design"),
+        charge_now: num("charge_now"),
+        charge_full: num("charge_full"),
+        charge_full_design: num("charge_full_design"),
+        voltage: num("voltage_now"),
+        power: abs("power_now"),
+        current: abs("current_now"),
+        cycle_count: num("cycle_count"),
+    }
+}
+
+/// read the details of a battery
+pub fn read_info(root: &Path, device: &str) -> BatInfo {
+    parse_info(|attr| read_attr(root, device, attr).ok())
+}
+
+/// Power supply class directory in a temp dir, removed on drop
+#[cfg(test)]
+pub struct FakeSysfs(tempfile::TempDir);
+
+#[cfg(test)]
+impl FakeSysfs {
+    pub fn empty() -> Self {
+        Self(tempfile::tempdir().unwrap())
+    }
+
+    /// With a discharging `BAT0` battery at 50% and an offline `AC` adapter
+    pub fn discharging() -> Self {
+        let sysfs = Self::empty();
+        sysfs.set("BAT0", "capacity", "50");
+        sysfs.set("BAT0", "status", "Discharging");
+        sysfs.set("BAT0", "power_now", "-12000000");
+        sysfs.set("AC", "online", "0");
+        sysfs
+    }
+
+    pub fn root(&self) -> &Path {
+        self.0.path()
+    }
+
+    /// Overwrite an attribute in place, so pollers never read it empty
+    pub fn set(&self, device: &str, attr: &str, val: &str) {
+        use std::io::Write;
+
+        fs::create_dir_all(self.root().join(device)).unwrap();
+        let val = format!("{val}\n");
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.root().join(device).join(attr))
+            .unwrap();
+        file.write_all(val.as_bytes()).unwrap();
+        file.set_len(val.len() as u64).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn capacity_is_clamped() {
+        assert_eq!(parse_capacity("BAT0", b"42\n").unwrap().get(), 42);
+        assert_eq!(parse_capacity("BAT0", b"104\n").unwrap().get(), 100);
+    }
+
+    #[test]
+    fn bogus_readings_are_errors() {
+        for raw in [&b"-1\n"[..], b"", b"\xff\n", b"abc"] {
+            let err = parse_capacity("BAT0", raw).unwrap_err();
+            assert!(err.is_bogus_reading(), "{err}");
+        }
+        assert!(parse_online("AC", b"yes\n").is_err());
+    }
+
+    #[test]
+    fn info_from_charge_attrs() {
+        let attrs = [
+            ("status", "Not charging"),
+            ("charge_now", "2000000"),
+            ("charge_full", "4000000"),
+            ("charge_full_design", "5000000"),
+            ("voltage_now", "12000000"),
+            ("current_now", "-1500000"),
+            ("cycle_count", "bogus"),
+        ];
+        let info = parse_info(|name| {
+            let (_, val) = attrs.iter().find(|(attr, _)| *attr == name)?;
+            Some(val.to_string())
+        });
+        assert_eq!(info.status, ChargeStatus::NotCharging);
+        assert_eq!(info.energy_now, None);
+        assert_eq!(info.current, Some(1_500_000));
+        assert_eq!(info.cycle_count, None);
+        assert_eq!(info.health(), Some(80));
+        // 1.5 A at 12 V
+        assert_eq!(info.power(), Some(18_000_000));
+    }
+
+    #[test]
+    fn online_values() {
+        assert_eq!(
+            parse_online("AC", b"0\n").unwrap(),
+            AdapterStatus::Disconnected
+        );
+        assert_eq!(
+            parse_online("AC", b"1\n").unwrap(),
+            AdapterStatus::Connected
+        );
+        assert_eq!(parse_online("USB", b"2").unwrap(), AdapterStatus::Connected);
+    }
+}
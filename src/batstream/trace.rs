@@ -0,0 +1,321 @@
+// Copyright 2023 developers of the `batmon` project
+// SPDX-License-Identifier: MPL-2.0
+
+//! Recording and replaying of battery event traces
+//!
+//! Traces have one event per line, prefixed by the seconds since the recording started:
+//! ```text
+//! 0.004 adapter disconnected
+//! 0.005 battery BAT0 50
+//! 0.005 status BAT0 discharging energy_now=24000000 power=12000000
+//! ```
+//! Lines starting with `#` are comments, backend errors are recorded as such
+use std::{
+    fmt::Write as _,
+    fs,
+    io::{self, LineWriter, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_stream::stream;
+use futures_lite::{Stream, StreamExt};
+use log::warn;
+use thiserror::Error;
+use tokio::time::{self, Instant};
+
+use super::{AdapterStatus, BatEvent, BatInfo, BatName, BatStream, ChargeStatus};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to read {}: {1}", .0.display())]
+    Io(PathBuf, #[source] io::Error),
+    #[error("{}:{line}: {msg}", .path.display())]
+    Invalid {
+        path: PathBuf,
+        line: usize,
+        msg: String,
+    },
+}
+
+fn status_str(status: ChargeStatus) -> &'static str {
+    match status {
+        ChargeStatus::Charging => "charging",
+        ChargeStatus::Discharging => "discharging",
+        ChargeStatus::Full => "full",
+        ChargeStatus::NotCharging => "not-charging",
+        ChargeStatus::Unknown => "unknown",
+    }
+}
+
+/// Optional `BatInfo` fields, by their trace key
+fn info_fields(info: &mut BatInfo) -> [(&'static str, &mut Option<u64>); 10] {
+    [
+        ("energy_now", &mut info.energy_now),
+        ("energy_full", &mut info.energy_full),
+        ("energy_full_design", &mut info.energy_full_design),
+        ("charge_now", &mut info.charge_now),
+        ("charge_full", &mut info.charge_full),
+        ("charge_full_design", &mut info.charge_full_design),
+        ("voltage", &mut info.voltage),
+        ("power", &mut info.power),
+        ("current", &mut info.current),
+        ("cycle_count", &mut info.cycle_count),
+    ]
+}
+
+/// Format an event as a trace line, without the newline
+pub fn format_event(at: Duration, event: &BatEvent) -> String {
+    let secs = at.as_secs_f64();
+    match event {
+        BatEvent::Adapter(AdapterStatus::Connected) => format!("{secs:.3} adapter connected"),
+        BatEvent::Adapter(AdapterStatus::Disconnected) => {
+            format!("{secs:.3} adapter disconnected")
+        }
+        BatEvent::Battery(name, lvl) => format!("{secs:.3} battery {name} {lvl}"),
+        BatEvent::Status(name, info) => {
+            let mut line = format!("{secs:.3} status {name} {}", status_str(info.status));
+            let mut info = info.clone();
+            for (key, val) in info_fields(&mut info) {
+                if let Some(val) = val {
+                    let _ = write!(line, " {key}={val}");
+                }
+            }
+            line
+        }
+    }
+}
+
+/// Parse a trace line, `None` for comments and blank lines
+pub fn parse_line(line: &str) -> Result<Option<(Duration, BatEvent)>, String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+    let mut words = line.split_whitespace();
+    let mut next = |what: &str| words.next().ok_or_else(|| format!("missing {what}"));
+
+    let at = next("timestamp")?;
+    let at = at
+        .parse()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| format!("invalid timestamp {at:?}"))?;
+    let event = match next("event")? {
+        "adapter" => BatEvent::Adapter(match next("adapter status")? {
+            "connected" => AdapterStatus::Connected,
+            "disconnected" => AdapterStatus::Disconnected,
+            status => return Err(format!("invalid adapter status {status:?}")),
+        }),
+        "battery" => {
+            let name = next("battery")?.into();
+            let lvl = next("level")?;
+            let lvl = lvl.parse().map_err(|_| format!("invalid level {lvl:?}"))?;
+            BatEvent::Battery(name, lvl)
+        }
+        "status" => {
+            let name: BatName = next("battery")?.into();
+            let mut info = BatInfo {
+                status: match next("status")? {
+                    "charging" => ChargeStatus::Charging,
+                    "discharging" => ChargeStatus::Discharging,
+                    "full" => ChargeStatus::Full,
+                    "not-charging" => ChargeStatus::NotCharging,
+                    "unknown" => ChargeStatus::Unknown,
+                    status => return Err(format!("invalid status {status:?}")),
+                },
+                ..Default::default()
+            };
+            for field in words {
+                let (key, val) = field
+                    .split_once('=')
+                    .ok_or_else(|| format!("expected key=value, got {field:?}"))?;
+                let (_, slot) = info_fields(&mut info)
+                    .into_iter()
+                    .find(|(k, _)| *k == key)
+                    .ok_or_else(|| format!("unknown key {key:?}"))?;
+                *slot = Some(val.parse().map_err(|_| format!("invalid {key} {val:?}"))?);
+            }
+            BatEvent::Status(name, info)
+        }
+        event => return Err(format!("unknown event {event:?}")),
+    };
+    Ok(Some((at, event)))
+}
+
+/// Events loaded from a trace file
+#[derive(Debug, Clone, Default)]
+pub struct Trace {
+    events: Vec<(Duration, BatEvent)>,
+}
+
+impl Trace {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let src = fs::read_to_string(path).map_err(|e| Error::Io(path.into(), e))?;
+        let mut events = Vec::new();
+        for (i, line) in src.lines().enumerate() {
+            let parsed = parse_line(line).map_err(|msg| Error::Invalid {
+                path: path.into(),
+                line: i + 1,
+                msg,
+            })?;
+            events.extend(parsed);
+        }
+        Ok(Self { events })
+    }
+
+    /// The batteries appearing in the trace, in order of appearance
+    pub fn batteries(&self) -> Vec<BatName> {
+        let mut batteries: Vec<BatName> = Vec::new();
+        for (_, event) in &self.events {
+            if let BatEvent::Battery(name, _) = event {
+                if !batteries.contains(name) {
+                    batteries.push(name.clone());
+                }
+            }
+        }
+        batteries
+    }
+}
+
+/// Replay the trace events at their timestamps, `speed` times faster, then end
+pub fn replay_stream(
+    trace: Trace,
+    speed: f64,
+) -> impl Stream<Item = crate::error::Result<BatEvent>> {
+    stream! {
+        let start = Instant::now();
+        for (at, event) in trace.events {
+            time::sleep_until(start + at.div_f64(speed)).await;
+            yield Ok(event);
+        }
+    }
+}
+
+/// Writes every event of the streams it wraps to a trace file
+#[derive(Clone)]
+pub struct Recorder {
+    start: Instant,
+    out: Arc<Mutex<LineWriter<Box<dyn Write + Send>>>>,
+}
+
+impl Recorder {
+    /// Record to `path`, truncating it
+    pub fn create(path: &Path) -> io::Result<Self> {
+        Ok(Self::new(Box::new(fs::File::create(path)?)))
+    }
+
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        Self {
+            start: Instant::now(),
+            out: Arc::new(Mutex::new(LineWriter::new(out))),
+        }
+    }
+
+    fn write(&self, item: &crate::error::Result<BatEvent>) {
+        let at = self.start.elapsed();
+        let line = match item {
+            Ok(event) => format_event(at, event),
+            Err(e) => format!("# {:.3} error: {e}", at.as_secs_f64()),
+        };
+        let mut out = self.out.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = writeln!(out, "{line}") {
+            warn!("failed to record event: {e}");
+        }
+    }
+
+    /// Record the events of `stream` as they are received
+    pub fn record<'a>(&self, stream: BatStream<'a>) -> BatStream<'a> {
+        let recorder = self.clone();
+        Box::pin(stream.inspect(move |item| recorder.write(item)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::batstream::BatLvl;
+
+    fn events() -> Vec<BatEvent> {
+        let info = BatInfo {
+            status: ChargeStatus::NotCharging,
+            energy_now: Some(24_000_000),
+            power: Some(0),
+            ..Default::default()
+        };
+        vec![
+            BatEvent::Adapter(AdapterStatus::Disconnected),
+            BatEvent::Battery("BAT0".into(), BatLvl::new(50).unwrap()),
+            BatEvent::Status("BAT0".into(), info),
+        ]
+    }
+
+    #[test]
+    fn events_round_trip() {
+        for (i, event) in events().into_iter().enumerate() {
+            let at = Duration::from_millis(1500 * i as u64);
+            let line = format_event(at, &event);
+            let (parsed_at, parsed) = parse_line(&line).unwrap().unwrap();
+            assert_eq!(parsed_at, at);
+            assert_eq!(format!("{parsed:?}"), format!("{event:?}"), "{line}");
+        }
+        assert_eq!(
+            format_event(Duration::from_millis(1500), &events()[2]),
+            "1.500 status BAT0 not-charging energy_now=24000000 power=0"
+        );
+    }
+
+    #[test]
+    fn invalid_lines() {
+        assert!(matches!(parse_line("# 1.000 error: gone"), Ok(None)));
+        for line in [
+            "1.0",
+            "-1 battery BAT0 50",
+            "1.0 battery BAT0 101",
+            "1.0 adapter plugged",
+            "1.0 status BAT0 full power",
+            "1.0 status BAT0 full watts=3",
+            "1.0 reboot",
+        ] {
+            assert!(parse_line(line).is_err(), "{line}");
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn recording_is_replayed() {
+        #[derive(Clone, Default)]
+        struct Buf(Arc<Mutex<Vec<u8>>>);
+
+        impl Write for Buf {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buf = Buf::default();
+        let recorder = Recorder::new(Box::new(buf.clone()));
+        let source = futures_lite::stream::iter(events()).then(|ev| async {
+            time::sleep(Duration::from_secs(2)).await;
+            Ok(ev)
+        });
+        let recorded: Vec<_> = recorder.record(Box::pin(source)).collect().await;
+        assert_eq!(recorded.len(), 3);
+
+        let src = String::from_utf8(buf.0.lock().unwrap().clone()).unwrap();
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), src).unwrap();
+        let trace = Trace::load(file.path()).unwrap();
+        assert_eq!(trace.batteries(), [BatName::from("BAT0")]);
+
+        let start = Instant::now();
+        let replayed: Vec<_> = replay_stream(trace, 2.0).collect().await;
+        assert_eq!(replayed.len(), 3);
+        // Recorded at 2, 4 and 6 seconds
+        assert_eq!(start.elapsed(), Duration::from_secs(3));
+    }
+}
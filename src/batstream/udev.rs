@@ -3,7 +3,7 @@
 
 //! Udev based battery event stream
 use std::{
-    io,
+    collections::VecDeque,
     path::{Path, PathBuf},
     pin::Pin,
     task::{Context, Poll},
@@ -13,95 +13,217 @@ use futures_lite::{ready, Stream, StreamExt};
 use tokio_udev::{AsyncMonitorSocket, Device, Event, EventType, MonitorBuilder};
 
 use super::{
-    sysfs::{BAT_BASE_PATH, BAT_SUBSYS},
-    udev_bat::extract_battery_cap,
-    AdapterStatus, BatEvent, BatLvl,
+    sysfs::BAT_SUBSYS,
+    udev_bat::{extract_adapter_status, extract_battery_cap, extract_battery_info, PowerSupply},
+    BatEvent, BatName,
 };
+use crate::error::Result;
 
-/// Udev based battery event stream
-pub struct UdevStream {
-    /// Path to battery device
-    battery_path: PathBuf,
+/// The monitored devices, and the events read from them not yet yielded
+struct Devices {
+    /// Paths to battery devices
+    battery_paths: Vec<(PathBuf, BatName)>,
     /// Path to adapter device
     adapter_path: PathBuf,
-    /// For pushing the current battery level
-    first_lvl: Option<BatLvl>,
+    /// The current readings, then the status following a battery reading
+    pending: VecDeque<Result<BatEvent>>,
+}
+
+impl Devices {
+    /// Queue the events of a changed device
+    /// ignores devices that aren't monitored
+    fn handle_change(&mut self, syspath: &Path, device: &impl PowerSupply) {
+        if syspath == self.adapter_path {
+            self.pending
+                .push_back(extract_adapter_status(device).map(BatEvent::Adapter));
+            return;
+        }
+
+        let Some((_, name)) = self.battery_paths.iter().find(|(path, _)| syspath == path) else {
+            return;
+        };
+        let events = battery_events(name, device);
+        self.pending.extend(events);
+    }
+}
+
+/// Udev based battery event stream
+pub struct UdevStream {
+    devices: Devices,
     monitor: AsyncMonitorSocket,
 }
 
 impl UdevStream {
-    pub fn new(battery_dev: impl AsRef<Path>, adapter_dev: impl AsRef<Path>) -> io::Result<Self> {
-        let battery_path = Path::new(BAT_BASE_PATH).join(battery_dev);
-        let adapter_path = Path::new(BAT_BASE_PATH).join(adapter_dev);
-
-        let battery = Device::from_syspath(&battery_path)?;
-        let _adapter = Device::from_syspath(&adapter_path)?;
+    /// `root` is the power supply class directory, udev requires it to be under `/sys`
+    ///
+    /// The current readings are streamed first, starting with the adapter
+    pub fn new(
+        root: &Path,
+        battery_devs: &[BatName],
+        adapter_dev: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let adapter_path = root.join(adapter_dev);
+        let adapter = Device::from_syspath(&adapter_path)?;
+
+        let mut battery_paths = Vec::with_capacity(battery_devs.len());
+        let mut pending = VecDeque::with_capacity(1 + battery_devs.len() * 2);
+        pending.push_back(extract_adapter_status(&adapter).map(BatEvent::Adapter));
+        for name in battery_devs {
+            let battery_path = root.join(&**name);
+            let battery = Device::from_syspath(&battery_path)?;
+            pending.extend(battery_events(name, &battery));
+            battery_paths.push((battery_path, name.clone()));
+        }
 
         let monitor = MonitorBuilder::new()?
             .match_subsystem(BAT_SUBSYS)?
             .listen()?
             .try_into()?;
-        let first_lvl = extract_battery_cap(&battery);
 
         Ok(Self {
-            battery_path,
-            adapter_path,
-            first_lvl: Some(first_lvl),
+            devices: Devices {
+                battery_paths,
+                adapter_path,
+                pending,
+            },
             monitor,
         })
     }
 
-    /// extract adapter status
-    fn handle_adapter(ev: &Device) -> AdapterStatus {
-        if ev
-            .property_value("POWER_SUPPLY_ONLINE")
-            .expect("adapter does not advertise online?!")
-            == "1"
-        {
-            AdapterStatus::Connected
-        } else {
-            AdapterStatus::Disconnected
-        }
-    }
-
     /// Handle udev event
     /// ignores if not a battery or adapter related event
-    fn handle_event(&self, event: Event) -> Option<BatEvent> {
+    fn handle_event(&mut self, event: Event) {
         if event.event_type() != EventType::Change {
-            return None;
-        }
-
-        if event.syspath() == self.battery_path {
-            Some(BatEvent::Battery(extract_battery_cap(&event)))
-        } else if event.syspath() == self.adapter_path {
-            Some(BatEvent::Adapter(Self::handle_adapter(&event)))
-        } else {
-            None
+            return;
         }
+        self.devices.handle_change(event.syspath(), &*event);
     }
 }
 
+/// The battery level, followed by the battery details
+fn battery_events(name: &BatName, battery: &impl PowerSupply) -> [Result<BatEvent>; 2] {
+    [
+        extract_battery_cap(battery).map(|lvl| BatEvent::Battery(name.clone(), lvl)),
+        Ok(BatEvent::Status(
+            name.clone(),
+            extract_battery_info(battery),
+        )),
+    ]
+}
+
 impl Stream for UdevStream {
-    type Item = io::Result<BatEvent>;
+    type Item = Result<BatEvent>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        if let Some(lvl) = self.first_lvl.take() {
-            return Poll::Ready(Some(Ok(BatEvent::Battery(lvl))));
-        }
+        loop {
+            if let Some(ev) = self.devices.pending.pop_front() {
+                return Poll::Ready(Some(ev));
+            }
 
-        let res = loop {
             let event = match ready!(self.monitor.poll_next(cx)) {
                 Some(Ok(event)) => event,
-                Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+                Some(Err(err)) => return Poll::Ready(Some(Err(err.into()))),
                 None => return Poll::Ready(None),
             };
+            self.handle_event(event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::{OsStr, OsString};
+
+    use super::*;
+    use crate::{
+        batstream::{AdapterStatus, ChargeStatus},
+        error::Error,
+    };
 
-            match self.handle_event(event) {
-                Some(event) => break event,
-                None => continue,
+    struct FakeDevice {
+        name: OsString,
+        props: Vec<(&'static str, OsString)>,
+    }
+
+    impl FakeDevice {
+        fn new(name: &str, props: &[(&'static str, &str)]) -> Self {
+            Self {
+                name: name.into(),
+                props: props.iter().map(|&(k, v)| (k, v.into())).collect(),
             }
+        }
+    }
+
+    impl PowerSupply for FakeDevice {
+        fn sysname(&self) -> &OsStr {
+            &self.name
+        }
+
+        fn property_value(&self, property: &str) -> Option<&OsStr> {
+            let (_, val) = self.props.iter().find(|(k, _)| *k == property)?;
+            Some(val)
+        }
+    }
+
+    fn devices() -> Devices {
+        Devices {
+            battery_paths: vec![("/sys/class/power_supply/BAT0".into(), "BAT0".into())],
+            adapter_path: "/sys/class/power_supply/AC".into(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    #[test]
+    fn changes_are_queued() {
+        let mut devices = devices();
+        let battery = FakeDevice::new(
+            "BAT0",
+            &[
+                ("POWER_SUPPLY_CAPACITY", "42"),
+                ("POWER_SUPPLY_STATUS", "Discharging"),
+                ("POWER_SUPPLY_POWER_NOW", "9000000"),
+            ],
+        );
+        devices.handle_change(Path::new("/sys/class/power_supply/BAT0"), &battery);
+        let adapter = FakeDevice::new("AC", &[("POWER_SUPPLY_ONLINE", "1")]);
+        devices.handle_change(Path::new("/sys/class/power_supply/AC"), &adapter);
+
+        let events: Vec<_> = devices.pending.drain(..).map(Result::unwrap).collect();
+        let [BatEvent::Battery(name, lvl), BatEvent::Status(_, info), BatEvent::Adapter(status)] =
+            &events[..]
+        else {
+            panic!("unexpected events {events:?}");
         };
+        assert_eq!((&**name, lvl.get()), ("BAT0", 42));
+        assert_eq!(info.status, ChargeStatus::Discharging);
+        assert_eq!(info.power, Some(9_000_000));
+        assert_eq!(*status, AdapterStatus::Connected);
+    }
+
+    #[test]
+    fn other_devices_are_ignored() {
+        let mut devices = devices();
+        let mouse = FakeDevice::new("hidpp_battery_0", &[("POWER_SUPPLY_CAPACITY", "80")]);
+        devices.handle_change(Path::new("/sys/class/power_supply/hidpp_battery_0"), &mouse);
+        assert!(devices.pending.is_empty());
+    }
 
-        Poll::Ready(Some(Ok(res)))
+    #[test]
+    fn missing_capacity_is_an_error() {
+        let mut devices = devices();
+        let battery = FakeDevice::new("BAT0", &[]);
+        devices.handle_change(Path::new("/sys/class/power_supply/BAT0"), &battery);
+        assert!(matches!(
+            devices.pending.pop_front(),
+            Some(Err(Error::MissingProperty {
+                property: "POWER_SUPPLY_CAPACITY",
+                ..
+            }))
+        ));
+        // The status is still reported
+        assert!(matches!(
+            devices.pending.pop_front(),
+            Some(Ok(BatEvent::Status(..)))
+        ));
     }
 }
@@ -1,16 +1,57 @@
 // Copyright 2023 developers of the `batmon` project
 // SPDX-License-Identifier: MPL-2.0
 
+use std::{ffi::OsStr, os::unix::ffi::OsStrExt};
+
 use tokio_udev::Device;
 
-use super::BatLvl;
+use super::{
+    sysfs::{parse_capacity, parse_info, parse_online},
+    AdapterStatus, BatInfo, BatLvl,
+};
+use crate::error::{Error, Result};
+
+/// Device exposing `POWER_SUPPLY_*` properties, implemented by udev devices
+pub trait PowerSupply {
+    fn sysname(&self) -> &OsStr;
+    fn property_value(&self, property: &str) -> Option<&OsStr>;
+}
+
+impl PowerSupply for Device {
+    fn sysname(&self) -> &OsStr {
+        Device::sysname(self)
+    }
+
+    fn property_value(&self, property: &str) -> Option<&OsStr> {
+        Device::property_value(self, property)
+    }
+}
+
+fn property<'a>(ev: &'a impl PowerSupply, property: &'static str) -> Result<&'a [u8]> {
+    ev.property_value(property)
+        .map(|val| val.as_bytes())
+        .ok_or_else(|| Error::MissingProperty {
+            device: ev.sysname().to_string_lossy().into(),
+            property,
+        })
+}
 
 /// extract battery capacity
-pub fn extract_battery_cap(ev: &Device) -> BatLvl {
-    ev.property_value("POWER_SUPPLY_CAPACITY")
-        .expect("battery does not advertise capacity?!")
-        .to_str()
-        .expect("battery capacity is not valid utf8?!")
-        .parse::<BatLvl>()
-        .expect("battery capacity is not a valid u8?!")
+pub fn extract_battery_cap(ev: &impl PowerSupply) -> Result<BatLvl> {
+    let raw = property(ev, "POWER_SUPPLY_CAPACITY")?;
+    parse_capacity(&ev.sysname().to_string_lossy(), raw)
+}
+
+/// extract battery details, from the `POWER_SUPPLY_*` properties
+pub fn extract_battery_info(ev: &impl PowerSupply) -> BatInfo {
+    parse_info(|attr| {
+        let key = format!("POWER_SUPPLY_{}", attr.to_ascii_uppercase());
+        Some(ev.property_value(&key)?.to_string_lossy().into())
+    })
+}
+
+/// extract adapter status
+pub fn extract_adapter_status(ev: &impl PowerSupply) -> Result<AdapterStatus> {
+    let raw = property(ev, "POWER_SUPPLY_ONLINE")?;
+    parse_online(&ev.sysname().to_string_lossy(), raw)
 }
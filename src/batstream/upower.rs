@@ -0,0 +1,312 @@
+// Copyright 2023 developers of the `batmon` project
+// SPDX-License-Identifier: MPL-2.0
+
+//! UPower D-Bus battery event stream
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+    vec,
+};
+
+use futures_lite::{Stream, StreamExt};
+use zbus::{dbus_proxy, Connection};
+
+use super::{AdapterStatus, BatEvent, BatLvl, BatName, BatStream, Dedup};
+use crate::error::Result;
+
+#[dbus_proxy(
+    interface = "org.freedesktop.UPower",
+    default_service = "org.freedesktop.UPower",
+    default_path = "/org/freedesktop/UPower"
+)]
+trait UPower {
+    #[dbus_proxy(property)]
+    fn on_battery(&self) -> zbus::Result<bool>;
+}
+
+#[dbus_proxy(
+    interface = "org.freedesktop.UPower.Device",
+    default_service = "org.freedesktop.UPower"
+)]
+trait Device {
+    #[dbus_proxy(property)]
+    fn percentage(&self) -> zbus::Result<f64>;
+}
+
+/// UPower's composite battery, combining every battery
+pub const DISPLAY_DEVICE: &str = "DisplayDevice";
+
+/// UPower object path of a battery, e.g `battery_BAT0`, or of the display device
+fn device_path(name: &str) -> String {
+    if name == DISPLAY_DEVICE {
+        return format!("/org/freedesktop/UPower/devices/{DISPLAY_DEVICE}");
+    }
+    format!("/org/freedesktop/UPower/devices/battery_{name}")
+}
+
+fn lvl(percentage: f64) -> BatLvl {
+    BatLvl::new_saturating(percentage.round().clamp(0.0, 100.0) as u8)
+}
+
+fn adapter(on_battery: bool) -> AdapterStatus {
+    if on_battery {
+        AdapterStatus::Disconnected
+    } else {
+        AdapterStatus::Connected
+    }
+}
+
+/// Battery events from UPower's PropertiesChanged signals
+pub struct UPowerStream {
+    /// For pushing the current adapter status and battery levels
+    first_events: vec::IntoIter<BatEvent>,
+    /// Property changes of the adapter and every battery
+    changes: Vec<BatStream<'static>>,
+    /// The subscriptions start with the current values, already streamed
+    dedup: Dedup,
+}
+
+impl UPowerStream {
+    /// Subscribe to UPower on the system bus,
+    /// [`DISPLAY_DEVICE`] follows the combined level of every battery
+    pub async fn new(battery_devs: &[BatName]) -> Result<Self> {
+        Self::with_connection(&Connection::system().await?, battery_devs).await
+    }
+
+    pub async fn with_connection(bus: &Connection, battery_devs: &[BatName]) -> Result<Self> {
+        // Subscribe before reading the properties, so no change is missed in between
+        let upower = UPowerProxy::new(bus).await?;
+        let on_battery = upower
+            .receive_on_battery_changed()
+            .await
+            .then(|change| async move { Ok(BatEvent::Adapter(adapter(change.get().await?))) });
+        let mut changes: Vec<BatStream> = vec![Box::pin(on_battery)];
+        let mut devices = Vec::with_capacity(battery_devs.len());
+        for name in battery_devs {
+            let device = DeviceProxy::builder(bus)
+                .path(device_path(name))?
+                .build()
+                .await?;
+            let percentage = device.receive_percentage_changed().await.then({
+                let name = name.clone();
+                move |change| {
+                    let name = name.clone();
+                    async move { Ok(BatEvent::Battery(name, lvl(change.get().await?))) }
+                }
+            });
+            changes.push(Box::pin(percentage));
+            devices.push((name, device));
+        }
+
+        let mut first_events = vec![BatEvent::Adapter(adapter(upower.on_battery().await?))];
+        for (name, device) in devices {
+            first_events.push(BatEvent::Battery(
+                name.clone(),
+                lvl(device.percentage().await?),
+            ));
+        }
+
+        Ok(Self {
+            first_events: first_events.into_iter(),
+            changes,
+            dedup: Dedup::default(),
+        })
+    }
+}
+
+impl Stream for UPowerStream {
+    type Item = Result<BatEvent>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        if let Some(ev) = this.first_events.next() {
+            this.dedup.is_new(&ev);
+            return Poll::Ready(Some(Ok(ev)));
+        }
+
+        'poll: loop {
+            for changes in &mut this.changes {
+                if let Poll::Ready(ev) = changes.poll_next(cx) {
+                    match &ev {
+                        Some(Ok(change)) if !this.dedup.is_new(change) => continue 'poll,
+                        _ => return Poll::Ready(ev),
+                    }
+                }
+            }
+            return Poll::Pending;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        process::{Child, Command, Stdio},
+    };
+
+    use tempfile::TempDir;
+    use zbus::{dbus_interface, ConnectionBuilder, SignalContext};
+
+    use super::*;
+    use crate::error::Error;
+
+    struct MockUPower {
+        on_battery: bool,
+    }
+
+    #[dbus_interface(name = "org.freedesktop.UPower")]
+    impl MockUPower {
+        #[dbus_interface(property)]
+        fn on_battery(&self) -> bool {
+            self.on_battery
+        }
+    }
+
+    struct MockDevice {
+        percentage: f64,
+    }
+
+    #[dbus_interface(name = "org.freedesktop.UPower.Device")]
+    impl MockDevice {
+        #[dbus_interface(property)]
+        fn percentage(&self) -> f64 {
+            self.percentage
+        }
+    }
+
+    /// Private session bus, killed on drop
+    struct PrivateBus {
+        daemon: Child,
+        address: String,
+        /// Holds the bus socket
+        _dir: TempDir,
+    }
+
+    impl PrivateBus {
+        /// Start a bus, `dbus-daemon` must be installed
+        fn start() -> Self {
+            let dir = tempfile::tempdir().unwrap();
+            let socket = dir.path().join("bus");
+            let mut daemon = Command::new("dbus-daemon")
+                .arg("--session")
+                .arg("--nofork")
+                .arg("--print-address=1")
+                .arg(format!("--address=unix:path={}", socket.display()))
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .expect("failed to start dbus-daemon, the UPower tests require it");
+            let mut address = String::new();
+            BufReader::new(daemon.stdout.take().unwrap())
+                .read_line(&mut address)
+                .unwrap();
+            Self {
+                daemon,
+                address: address.trim_end().into(),
+                _dir: dir,
+            }
+        }
+
+        fn connect(&self) -> ConnectionBuilder<'static> {
+            ConnectionBuilder::address(self.address.as_str()).unwrap()
+        }
+    }
+
+    impl Drop for PrivateBus {
+        fn drop(&mut self) {
+            let _ = self.daemon.kill();
+            let _ = self.daemon.wait();
+        }
+    }
+
+    /// Mock UPower exposing `BAT0`, and the display device
+    async fn mock_upower(bus: &PrivateBus) -> Connection {
+        bus.connect()
+            .name("org.freedesktop.UPower")
+            .unwrap()
+            .serve_at("/org/freedesktop/UPower", MockUPower { on_battery: true })
+            .unwrap()
+            .serve_at(device_path("BAT0"), MockDevice { percentage: 80.4 })
+            .unwrap()
+            .serve_at(device_path(DISPLAY_DEVICE), MockDevice { percentage: 65.0 })
+            .unwrap()
+            .build()
+            .await
+            .unwrap()
+    }
+
+    async fn set_percentage(server: &Connection, name: &str, percentage: f64) {
+        let iface = server
+            .object_server()
+            .interface::<_, MockDevice>(device_path(name))
+            .await
+            .unwrap();
+        iface.get_mut().await.percentage = percentage;
+        let ctxt = SignalContext::new(server, device_path(name)).unwrap();
+        iface.get().await.percentage_changed(&ctxt).await.unwrap();
+    }
+
+    async fn set_on_battery(server: &Connection, on_battery: bool) {
+        let iface = server
+            .object_server()
+            .interface::<_, MockUPower>("/org/freedesktop/UPower")
+            .await
+            .unwrap();
+        iface.get_mut().await.on_battery = on_battery;
+        let ctxt = SignalContext::new(server, "/org/freedesktop/UPower").unwrap();
+        iface.get().await.on_battery_changed(&ctxt).await.unwrap();
+    }
+
+    fn summary(ev: Option<Result<BatEvent>>) -> String {
+        match ev.unwrap().unwrap() {
+            BatEvent::Battery(name, lvl) => format!("{name}:{lvl}"),
+            BatEvent::Adapter(status) => format!("{status:?}"),
+            BatEvent::Status(name, info) => format!("{name}:{}", info.status),
+        }
+    }
+
+    #[tokio::test]
+    async fn property_changes_are_streamed() {
+        let bus = PrivateBus::start();
+        let server = mock_upower(&bus).await;
+        let client = bus.connect().build().await.unwrap();
+        let mut stream = UPowerStream::with_connection(&client, &["BAT0".into()])
+            .await
+            .unwrap();
+        assert_eq!(summary(stream.next().await), "Disconnected");
+        assert_eq!(summary(stream.next().await), "BAT0:80");
+
+        set_percentage(&server, "BAT0", 79.4).await;
+        assert_eq!(summary(stream.next().await), "BAT0:79");
+        set_percentage(&server, "BAT0", 120.0).await;
+        assert_eq!(summary(stream.next().await), "BAT0:100");
+        set_on_battery(&server, false).await;
+        assert_eq!(summary(stream.next().await), "Connected");
+    }
+
+    #[tokio::test]
+    async fn display_device_is_streamed() {
+        let bus = PrivateBus::start();
+        let server = mock_upower(&bus).await;
+        set_on_battery(&server, false).await;
+        let client = bus.connect().build().await.unwrap();
+        let mut stream = UPowerStream::with_connection(&client, &[DISPLAY_DEVICE.into()])
+            .await
+            .unwrap();
+        assert_eq!(summary(stream.next().await), "Connected");
+        assert_eq!(summary(stream.next().await), "DisplayDevice:65");
+
+        set_percentage(&server, DISPLAY_DEVICE, 66.0).await;
+        assert_eq!(summary(stream.next().await), "DisplayDevice:66");
+    }
+
+    #[tokio::test]
+    async fn unknown_battery_is_an_error() {
+        let bus = PrivateBus::start();
+        let _server = mock_upower(&bus).await;
+        let client = bus.connect().build().await.unwrap();
+        let res = UPowerStream::with_connection(&client, &["BAT1".into()]).await;
+        assert!(matches!(res, Err(e @ Error::UPower(_)) if e.is_permanent()));
+    }
+}
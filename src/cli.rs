@@ -0,0 +1,101 @@
+// Copyright 2023 developers of the `batmon` project
+// SPDX-License-Identifier: MPL-2.0
+
+//! The `batmon` binary
+use std::process;
+
+use clap::Parser;
+
+use crate::{
+    aggregate::BatAggregate,
+    args::{Args, Backend},
+    batstream::trace::{Recorder, Trace},
+    config::Config,
+    error::Error,
+    estimate::Estimator,
+    monitor::Monitor,
+    notif::composite::Composite,
+    reload,
+    service::{stream_loop, StreamSource},
+};
+
+/// Exit code for errors restarting won't fix,
+/// an invalid configuration, missing devices or an unsupported backend
+const EXIT_PERMANENT: i32 = 2;
+/// Exit code for other unrecoverable errors
+const EXIT_FATAL: i32 = 1;
+
+/// Exit on an unrecoverable error
+fn fatal(msg: &str, e: impl std::fmt::Display) -> ! {
+    eprintln!("error: {msg}: {e}");
+    process::exit(EXIT_FATAL);
+}
+
+/// Exit on a backend error, with [`EXIT_PERMANENT`] if it is permanent
+fn backend_fatal(msg: &str, e: impl Into<Error>) -> ! {
+    let e = e.into();
+    eprintln!("error: {msg}: {e}");
+    process::exit(if e.is_permanent() {
+        EXIT_PERMANENT
+    } else {
+        EXIT_FATAL
+    });
+}
+
+/// Parse the CLI flags and monitor the batteries until an unrecoverable error
+pub async fn run() {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+    let args = Args::parse();
+    let config = match Config::load(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("error: {e}");
+            process::exit(EXIT_PERMANENT);
+        }
+    };
+    let replay = match &config.replay.file {
+        Some(path) if config.backend == Backend::Replay => {
+            Trace::load(path).unwrap_or_else(|e| fatal("failed to load the trace", e))
+        }
+        _ => Trace::default(),
+    };
+    let batteries = match config.backend {
+        // Use the batteries of the trace
+        Backend::Replay if config.battery.is_empty() => replay.batteries(),
+        _ => config
+            .batteries()
+            .unwrap_or_else(|e| backend_fatal("failed to find batteries", e)),
+    };
+    let recorder = args.record.as_ref().map(|path| {
+        Recorder::create(path).unwrap_or_else(|e| fatal("failed to create the trace", e))
+    });
+    let adapter = config
+        .adapter()
+        .unwrap_or_else(|e| backend_fatal("failed to find the adapter", e));
+    let aggregate = BatAggregate::new(&config.sysfs_root, &batteries);
+    let estimator = Estimator::new(&batteries);
+    let monitor = Monitor::new(&config, aggregate, estimator);
+    let source = StreamSource {
+        backend: config.backend,
+        sysfs_root: config.sysfs_root.clone(),
+        polling_interval: config.polling_interval,
+        reconcile_interval: config.reconcile_interval,
+        batteries,
+        adapter,
+        simulate: config.simulate.clone(),
+        replay,
+        replay_speed: config.replay.speed,
+        recorder,
+    };
+    let config = reload::spawn_reloader(args, config)
+        .unwrap_or_else(|e| fatal("failed to watch the config", e));
+
+    let backend_err = || format!("the {:?} backend failed", source.backend);
+    let stream = source
+        .open()
+        .await
+        .unwrap_or_else(|e| backend_fatal(&backend_err(), e));
+    if let Err(e) = stream_loop(&source, stream, config, Composite::new, monitor).await {
+        backend_fatal(&backend_err(), e);
+    }
+}
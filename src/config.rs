@@ -0,0 +1,1148 @@
+// Copyright 2023 developers of the `batmon` project
+// SPDX-License-Identifier: MPL-2.0
+
+//! Configuration file support
+use std::{
+    cmp::Reverse,
+    env, fs, io,
+    ops::Range,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use serde::Deserialize;
+use thiserror::Error;
+use toml::Spanned;
+
+use crate::{
+    action::Action,
+    args::{Args, Backend},
+    batstream::{
+        discovery,
+        simulate::{Phase, Profile},
+        sysfs::BAT_BASE_PATH,
+        AdapterStatus, BatLvl, BatName,
+    },
+    notif::{webhook, Sink},
+    priority::{ChargeLvl, ChargeThreshold, EvPriority, PriorityThreshold, Threshold},
+};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("failed to read {}: {1}", .0.display())]
+    Io(PathBuf, #[source] io::Error),
+    #[error("{}:{line}: {msg}", .path.display())]
+    Invalid {
+        path: PathBuf,
+        line: usize,
+        msg: String,
+    },
+    #[error("the replay backend requires a trace, set --replay or [replay] file")]
+    MissingTrace,
+    /// Invalid once the CLI flags are merged with the config file
+    #[error("invalid configuration: {0}")]
+    Conflict(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Default notification summary for custom thresholds
+const DEFAULT_SUMMARY: &str = "Low Battery";
+/// Default notification body for custom thresholds
+const DEFAULT_BODY: &str = "Battery level is low at {level}%";
+/// Default notification summary for charge thresholds
+const CHARGED_SUMMARY: &str = "Battery Charged";
+/// Default notification body for charge thresholds
+const CHARGED_BODY: &str = "Battery is charged to {level}%";
+/// Default notification body for the "full" charge threshold
+const FULL_BODY: &str = "Battery is fully charged";
+
+/// Shorthand threshold names from the most urgent,
+/// with the urgency of the custom threshold they stand for
+const SHORTHANDS: [(&str, EvPriority); 3] = [
+    ("critical", EvPriority::High),
+    ("very_low", EvPriority::Normal),
+    ("low", EvPriority::Low),
+];
+const WEBHOOK_URL_REQUIRED: &str = "the webhook sink requires [webhook] url";
+
+/// The first shorthand level lower than that of the more urgent one before it
+fn misordered<T>(
+    levels: impl IntoIterator<Item = (&'static str, BatLvl, T)>,
+) -> Option<(T, String)> {
+    let mut prev: Option<(&str, BatLvl)> = None;
+    for (name, level, tag) in levels {
+        if let Some((prev_name, prev_level)) = prev {
+            if prev_level > level {
+                let msg = format!("{name} threshold must not be lower than {prev_name} threshold");
+                return Some((tag, msg));
+            }
+        }
+        prev = Some((name, level));
+    }
+    None
+}
+
+/// The custom level threshold a shorthand stands for,
+/// the one with its urgency and the lowest level
+fn shorthand_index(thresholds: &[Threshold], urgency: EvPriority) -> Option<usize> {
+    thresholds
+        .iter()
+        .enumerate()
+        .filter(|(_, t)| t.urgency == urgency && t.minutes.is_none())
+        .min_by_key(|(_, t)| t.level)
+        .map(|(i, _)| i)
+}
+
+/// Charge threshold with the default notification
+fn charged(lvl: BatLvl) -> Threshold {
+    let body = if lvl == BatLvl::MAX {
+        FULL_BODY
+    } else {
+        CHARGED_BODY
+    };
+    Threshold::new(lvl, EvPriority::Low, CHARGED_SUMMARY, body)
+}
+
+/// Critical action threshold with the default notification
+fn critical_action(action: Action) -> Threshold {
+    let mut threshold = Threshold::new(
+        lvl(3),
+        EvPriority::High,
+        "Critical Battery",
+        "Battery level is critical at {level}%, running {action} in {grace} seconds",
+    );
+    threshold.action = Some(action);
+    threshold
+}
+
+/// Reminder interval from minutes, 0 disables reminders
+fn reminder(minutes: u64) -> Option<Duration> {
+    (minutes != 0).then(|| Duration::from_secs(minutes * 60))
+}
+
+fn merge_threshold(threshold: &mut Threshold, file: ThresholdFile) {
+    if let Some(level) = file.level {
+        threshold.level = level.into_inner();
+    }
+    threshold.minutes = file.minutes.or(threshold.minutes);
+    threshold.urgency = file.urgency.unwrap_or(threshold.urgency);
+    if let Some(summary) = file.summary {
+        threshold.summary = summary;
+    }
+    if let Some(body) = file.body {
+        threshold.body = body;
+    }
+    threshold.action = file.action.or(threshold.action.take());
+    if let Some(grace) = file.grace {
+        threshold.grace = Some(Duration::from_secs(grace));
+    }
+    if let Some(interval) = file.remind_interval {
+        threshold.remind_interval = reminder(interval);
+    }
+    threshold.remind_on_drop = file.remind_on_drop.unwrap_or(threshold.remind_on_drop);
+}
+
+#[derive(Debug, Clone)]
+pub struct NotificationConfig {
+    /// Application name reported to the notification server
+    pub appname: String,
+    pub timeout: Duration,
+    /// Sinks notifications are dispatched to
+    pub sinks: Vec<Sink>,
+    /// Include the estimated remaining time in battery notifications
+    pub estimate: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReplayConfig {
+    /// Trace file of the replay backend
+    pub file: Option<PathBuf>,
+    /// How many times faster than recorded the trace is replayed
+    pub speed: f64,
+}
+
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    /// `http://` url notifications are POSTed to
+    pub url: Option<String>,
+    pub timeout: Duration,
+}
+
+/// Commands run on events
+#[derive(Debug, Clone)]
+pub struct HooksConfig {
+    /// Run on every battery level event
+    pub battery: Vec<String>,
+    /// Run on every adapter event
+    pub adapter: Vec<String>,
+    /// Run when a threshold is reached
+    pub threshold: Vec<String>,
+    /// Run when the charging status of a battery changes
+    pub status: Vec<String>,
+    /// Hooks running for longer are killed
+    pub timeout: Duration,
+}
+
+impl HooksConfig {
+    pub fn is_empty(&self) -> bool {
+        [&self.battery, &self.adapter, &self.threshold, &self.status]
+            .iter()
+            .all(|cmds| cmds.is_empty())
+    }
+}
+
+/// batmon configuration, merged from the config file and CLI flags
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub battery: Vec<String>,
+    pub adapter: Option<String>,
+    /// Power supply class directory
+    pub sysfs_root: PathBuf,
+    pub backend: Backend,
+    pub polling_interval: Duration,
+    /// Polling interval of the hybrid backend
+    pub reconcile_interval: Duration,
+    /// Shorthand thresholds, used unless custom thresholds are set
+    pub low: Threshold,
+    pub very_low: Threshold,
+    pub critical: Threshold,
+    /// Custom thresholds
+    pub thresholds: Option<Vec<Threshold>>,
+    /// Threshold running a power action, in addition to the others
+    pub critical_action: Option<Threshold>,
+    /// Charge thresholds, notified while the adapter is connected
+    pub charged: Vec<Threshold>,
+    /// How far the level must move back past a reached threshold to re-arm it
+    pub hysteresis: u8,
+    /// How far the estimate must rise back past a reached time threshold to re-arm it
+    pub minutes_hysteresis: u64,
+    pub notification: NotificationConfig,
+    pub hooks: HooksConfig,
+    pub webhook: WebhookConfig,
+    /// Profile of the simulate backend
+    pub simulate: Profile,
+    pub replay: ReplayConfig,
+    /// Reload the config file when it changes
+    pub watch_config: bool,
+}
+
+fn lvl(lvl: u8) -> BatLvl {
+    BatLvl::new(lvl).unwrap()
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            battery: Vec::new(),
+            adapter: None,
+            sysfs_root: BAT_BASE_PATH.into(),
+            backend: Backend::Auto,
+            polling_interval: Duration::from_secs(5),
+            reconcile_interval: Duration::from_secs(60),
+            low: Threshold::new(lvl(30), EvPriority::Low, DEFAULT_SUMMARY, DEFAULT_BODY),
+            very_low: Threshold::new(lvl(15), EvPriority::Normal, DEFAULT_SUMMARY, DEFAULT_BODY),
+            critical: Threshold::new(
+                lvl(8),
+                EvPriority::High,
+                "Critical Battery",
+                "Battery level is critical at {level}%",
+            ),
+            thresholds: None,
+            critical_action: None,
+            charged: Vec::new(),
+            hysteresis: 2,
+            minutes_hysteresis: 5,
+            notification: NotificationConfig {
+                appname: "batmon".into(),
+                timeout: Duration::from_secs(5),
+                sinks: vec![Sink::Desktop],
+                estimate: true,
+            },
+            hooks: HooksConfig {
+                battery: Vec::new(),
+                adapter: Vec::new(),
+                threshold: Vec::new(),
+                status: Vec::new(),
+                timeout: Duration::from_secs(10),
+            },
+            webhook: WebhookConfig {
+                url: None,
+                timeout: Duration::from_secs(5),
+            },
+            simulate: Profile::default(),
+            replay: ReplayConfig {
+                file: None,
+                speed: 1.0,
+            },
+            watch_config: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ThresholdFile {
+    level: Option<Spanned<BatLvl>>,
+    minutes: Option<u64>,
+    urgency: Option<EvPriority>,
+    summary: Option<String>,
+    body: Option<String>,
+    action: Option<Action>,
+    /// seconds
+    grace: Option<u64>,
+    /// minutes
+    remind_interval: Option<u64>,
+    remind_on_drop: Option<bool>,
+}
+
+/// Custom threshold entry
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ThresholdEntry {
+    /// Required unless `minutes` is set
+    level: Option<Spanned<BatLvl>>,
+    minutes: Option<Spanned<u64>>,
+    #[serde(default = "default_urgency")]
+    urgency: EvPriority,
+    summary: Option<String>,
+    body: Option<String>,
+    action: Option<Action>,
+    /// seconds
+    grace: Option<u64>,
+    /// minutes
+    remind_interval: Option<u64>,
+    #[serde(default)]
+    remind_on_drop: bool,
+}
+
+fn default_urgency() -> EvPriority {
+    EvPriority::Normal
+}
+
+impl From<ThresholdEntry> for Threshold {
+    fn from(entry: ThresholdEntry) -> Self {
+        Self {
+            // Time thresholds are still reached once empty
+            level: entry.level.map_or(BatLvl::MIN, Spanned::into_inner),
+            minutes: entry.minutes.map(Spanned::into_inner),
+            urgency: entry.urgency,
+            summary: entry.summary.unwrap_or_else(|| DEFAULT_SUMMARY.into()),
+            body: entry.body.unwrap_or_else(|| DEFAULT_BODY.into()),
+            action: entry.action,
+            grace: entry.grace.map(Duration::from_secs),
+            remind_interval: entry.remind_interval.and_then(reminder),
+            remind_on_drop: entry.remind_on_drop,
+        }
+    }
+}
+
+/// Charge threshold entry
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ChargedEntry {
+    level: ChargeLvl,
+    urgency: Option<EvPriority>,
+    summary: Option<String>,
+    body: Option<String>,
+    action: Option<Action>,
+    /// seconds
+    grace: Option<u64>,
+}
+
+impl From<ChargedEntry> for Threshold {
+    fn from(entry: ChargedEntry) -> Self {
+        let mut threshold = charged(entry.level.0);
+        threshold.urgency = entry.urgency.unwrap_or(threshold.urgency);
+        if let Some(summary) = entry.summary {
+            threshold.summary = summary;
+        }
+        if let Some(body) = entry.body {
+            threshold.body = body;
+        }
+        threshold.action = entry.action;
+        threshold.grace = entry.grace.map(Duration::from_secs);
+        threshold
+    }
+}
+
+/// Threshold running a power action
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CriticalActionFile {
+    level: Option<BatLvl>,
+    action: Action,
+    /// seconds
+    grace: Option<u64>,
+    summary: Option<String>,
+    body: Option<String>,
+}
+
+impl From<CriticalActionFile> for Threshold {
+    fn from(file: CriticalActionFile) -> Self {
+        let mut threshold = critical_action(file.action);
+        if let Some(level) = file.level {
+            threshold.level = level;
+        }
+        threshold.grace = file.grace.map(Duration::from_secs);
+        if let Some(summary) = file.summary {
+            threshold.summary = summary;
+        }
+        if let Some(body) = file.body {
+            threshold.body = body;
+        }
+        threshold
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ThresholdsFile {
+    low: Option<Spanned<ThresholdFile>>,
+    very_low: Option<Spanned<ThresholdFile>>,
+    critical: Option<Spanned<ThresholdFile>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct NotificationFile {
+    appname: Option<String>,
+    timeout: Option<u64>,
+    sinks: Option<Spanned<Vec<Sink>>>,
+    estimate: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct HooksFile {
+    battery: Option<Vec<String>>,
+    adapter: Option<Vec<String>>,
+    threshold: Option<Vec<String>>,
+    status: Option<Vec<String>>,
+    /// seconds
+    timeout: Option<u64>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct WebhookFile {
+    url: Option<Spanned<String>>,
+    /// seconds
+    timeout: Option<u64>,
+}
+
+/// Phase of the simulate backend profile
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PhaseFile {
+    /// simulated minutes
+    minutes: Spanned<u64>,
+    /// whether the adapter is connected
+    adapter: bool,
+    /// percent per simulated minute
+    rate: f64,
+}
+
+impl From<PhaseFile> for Phase {
+    fn from(file: PhaseFile) -> Self {
+        Self {
+            duration: Duration::from_secs(file.minutes.into_inner() * 60),
+            adapter: if file.adapter {
+                AdapterStatus::Connected
+            } else {
+                AdapterStatus::Disconnected
+            },
+            rate: file.rate,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct SimulateFile {
+    level: Option<BatLvl>,
+    speed: Option<Spanned<f64>>,
+    /// simulated seconds
+    interval: Option<Spanned<u64>>,
+    noise: Option<BatLvl>,
+    seed: Option<u64>,
+    phase: Option<Vec<PhaseFile>>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ReplayFile {
+    file: Option<PathBuf>,
+    speed: Option<Spanned<f64>>,
+}
+
+/// On-disk representation of the config file
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    battery: Option<Vec<String>>,
+    adapter: Option<String>,
+    sysfs_root: Option<PathBuf>,
+    backend: Option<Backend>,
+    polling_interval: Option<Spanned<u64>>,
+    reconcile_interval: Option<Spanned<u64>>,
+    /// Shorthand thresholds
+    thresholds: ThresholdsFile,
+    /// Custom thresholds
+    threshold: Option<Vec<Spanned<ThresholdEntry>>>,
+    critical_action: Option<CriticalActionFile>,
+    charged: Option<Vec<ChargedEntry>>,
+    hysteresis: Option<BatLvl>,
+    /// minutes
+    minutes_hysteresis: Option<u64>,
+    notification: NotificationFile,
+    hooks: HooksFile,
+    webhook: WebhookFile,
+    simulate: SimulateFile,
+    replay: ReplayFile,
+    watch_config: Option<bool>,
+}
+
+/// Default config file location, `$XDG_CONFIG_HOME/batmon/config.toml`
+pub fn default_path() -> Option<PathBuf> {
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .or_else(|| Some(Path::new(&env::var_os("HOME")?).join(".config")))?;
+    Some(config_dir.join("batmon").join("config.toml"))
+}
+
+fn line_of(src: &str, span: Range<usize>) -> usize {
+    src[..span.start].matches('\n').count() + 1
+}
+
+impl ConfigFile {
+    fn parse(path: &Path, src: &str) -> Result<Self> {
+        let invalid = |span: Range<usize>, msg: String| Error::Invalid {
+            path: path.into(),
+            line: line_of(src, span),
+            msg,
+        };
+
+        let file: Self = toml::from_str(src)
+            .map_err(|e| invalid(e.span().unwrap_or(0..0), e.message().into()))?;
+
+        let intervals = [
+            ("polling_interval", &file.polling_interval),
+            ("reconcile_interval", &file.reconcile_interval),
+            ("simulate interval", &file.simulate.interval),
+        ];
+        for (name, interval) in intervals {
+            if let Some(interval) = interval {
+                if *interval.get_ref() == 0 {
+                    return Err(invalid(
+                        interval.span(),
+                        format!("{name} must be greater than 0"),
+                    ));
+                }
+            }
+        }
+
+        let speeds = [
+            ("simulate speed", &file.simulate.speed),
+            ("replay speed", &file.replay.speed),
+        ];
+        for (name, speed) in speeds {
+            if let Some(speed) = speed {
+                if !(*speed.get_ref() > 0.0 && speed.get_ref().is_finite()) {
+                    return Err(invalid(
+                        speed.span(),
+                        format!("{name} must be greater than 0"),
+                    ));
+                }
+            }
+        }
+        for phase in file.simulate.phase.iter().flatten() {
+            if *phase.minutes.get_ref() == 0 {
+                return Err(invalid(
+                    phase.minutes.span(),
+                    "phase minutes must be greater than 0".into(),
+                ));
+            }
+        }
+
+        let thresholds = &file.thresholds;
+        let shorthands = [&thresholds.critical, &thresholds.very_low, &thresholds.low];
+        if let Some(entries) = &file.threshold {
+            if let Some(Some(shorthand)) = shorthands.iter().find(|t| t.is_some()) {
+                return Err(invalid(
+                    shorthand.span(),
+                    "[thresholds.*] can't be combined with [[threshold]]".into(),
+                ));
+            }
+            for (i, spanned) in entries.iter().enumerate() {
+                let entry = spanned.get_ref();
+                let (level, minutes) = match (&entry.level, &entry.minutes) {
+                    (None, None) => {
+                        return Err(invalid(
+                            spanned.span(),
+                            "threshold requires a level or minutes".into(),
+                        ))
+                    }
+                    (_, Some(minutes)) if *minutes.get_ref() == 0 => {
+                        return Err(invalid(
+                            minutes.span(),
+                            "minutes must be greater than 0".into(),
+                        ))
+                    }
+                    (level, minutes) => (level.as_ref(), minutes.as_ref()),
+                };
+                let prev = &entries[..i];
+                if let Some(level) = level.filter(|l| {
+                    prev.iter().any(|e| {
+                        e.get_ref().level.as_ref().map(Spanned::get_ref) == Some(l.get_ref())
+                    })
+                }) {
+                    return Err(invalid(
+                        level.span(),
+                        format!("duplicate threshold level {}", level.get_ref()),
+                    ));
+                }
+                if let Some(minutes) = minutes.filter(|m| {
+                    prev.iter().any(|e| {
+                        e.get_ref().minutes.as_ref().map(Spanned::get_ref) == Some(m.get_ref())
+                    })
+                }) {
+                    return Err(invalid(
+                        minutes.span(),
+                        format!("duplicate threshold minutes {}", minutes.get_ref()),
+                    ));
+                }
+            }
+        }
+
+        if let Some(url) = &file.webhook.url {
+            if let Err(e) = webhook::check_url(url.get_ref()) {
+                return Err(invalid(url.span(), e.to_string()));
+            }
+        }
+        if let Some(sinks) = &file.notification.sinks {
+            if sinks.get_ref().contains(&Sink::Webhook) && file.webhook.url.is_none() {
+                return Err(invalid(sinks.span(), WEBHOOK_URL_REQUIRED.into()));
+            }
+        }
+
+        let levels = SHORTHANDS
+            .iter()
+            .zip(shorthands)
+            .filter_map(|((name, _), t)| {
+                let level = t.as_ref()?.get_ref().level.as_ref()?;
+                Some((*name, *level.get_ref(), level.span()))
+            });
+        if let Some((span, msg)) = misordered(levels) {
+            return Err(invalid(span, msg));
+        }
+
+        Ok(file)
+    }
+}
+
+impl Config {
+    /// Path of the config file in use
+    pub fn path(args: &Args) -> Option<PathBuf> {
+        args.config.clone().or_else(default_path)
+    }
+
+    /// Load the config file and merge it with the CLI flags,
+    /// CLI flags take precedence
+    pub fn load(args: &Args) -> Result<Self> {
+        let mut config = Self::default();
+
+        if let Some(path) = Self::path(args) {
+            match fs::read_to_string(&path) {
+                Ok(src) => config.merge_file(ConfigFile::parse(&path, &src)?),
+                // The default config file is optional
+                Err(e) if e.kind() == io::ErrorKind::NotFound && args.config.is_none() => {}
+                Err(e) => return Err(Error::Io(path, e)),
+            }
+        }
+        config.merge_args(args);
+        config.validate()?;
+        if config.backend == Backend::Replay && config.replay.file.is_none() {
+            return Err(Error::MissingTrace);
+        }
+
+        Ok(config)
+    }
+
+    fn merge_file(&mut self, file: ConfigFile) {
+        if let Some(battery) = file.battery {
+            self.battery = battery;
+        }
+        self.adapter = file.adapter.or(self.adapter.take());
+        if let Some(root) = file.sysfs_root {
+            self.sysfs_root = root;
+        }
+        self.backend = file.backend.unwrap_or(self.backend);
+        if let Some(interval) = file.polling_interval {
+            self.polling_interval = Duration::from_secs(interval.into_inner());
+        }
+        if let Some(interval) = file.reconcile_interval {
+            self.reconcile_interval = Duration::from_secs(interval.into_inner());
+        }
+        let shorthands = [
+            (&mut self.low, file.thresholds.low),
+            (&mut self.very_low, file.thresholds.very_low),
+            (&mut self.critical, file.thresholds.critical),
+        ];
+        for (threshold, file) in shorthands {
+            if let Some(file) = file {
+                merge_threshold(threshold, file.into_inner());
+            }
+        }
+        if let Some(entries) = file.threshold {
+            self.thresholds = Some(entries.into_iter().map(|e| e.into_inner().into()).collect());
+        }
+        if let Some(file) = file.critical_action {
+            self.critical_action = Some(file.into());
+        }
+        if let Some(entries) = file.charged {
+            self.charged = entries.into_iter().map(Into::into).collect();
+        }
+        if let Some(hysteresis) = file.hysteresis {
+            self.hysteresis = hysteresis.get();
+        }
+        self.minutes_hysteresis = file.minutes_hysteresis.unwrap_or(self.minutes_hysteresis);
+        if let Some(appname) = file.notification.appname {
+            self.notification.appname = appname;
+        }
+        if let Some(timeout) = file.notification.timeout {
+            self.notification.timeout = Duration::from_secs(timeout);
+        }
+        if let Some(sinks) = file.notification.sinks {
+            self.notification.sinks = sinks.into_inner();
+        }
+        self.notification.estimate = file
+            .notification
+            .estimate
+            .unwrap_or(self.notification.estimate);
+        if let Some(url) = file.webhook.url {
+            self.webhook.url = Some(url.into_inner());
+        }
+        if let Some(timeout) = file.webhook.timeout {
+            self.webhook.timeout = Duration::from_secs(timeout);
+        }
+        let hooks = file.hooks;
+        let cmds = [
+            (&mut self.hooks.battery, hooks.battery),
+            (&mut self.hooks.adapter, hooks.adapter),
+            (&mut self.hooks.threshold, hooks.threshold),
+            (&mut self.hooks.status, hooks.status),
+        ];
+        for (cmds, file) in cmds {
+            if let Some(file) = file {
+                *cmds = file;
+            }
+        }
+        if let Some(timeout) = hooks.timeout {
+            self.hooks.timeout = Duration::from_secs(timeout);
+        }
+        let simulate = file.simulate;
+        if let Some(level) = simulate.level {
+            self.simulate.level = level.get().into();
+        }
+        if let Some(speed) = simulate.speed {
+            self.simulate.speed = speed.into_inner();
+        }
+        if let Some(interval) = simulate.interval {
+            self.simulate.interval = Duration::from_secs(interval.into_inner());
+        }
+        if let Some(noise) = simulate.noise {
+            self.simulate.noise = noise.get();
+        }
+        self.simulate.seed = simulate.seed.unwrap_or(self.simulate.seed);
+        if let Some(phases) = simulate.phase {
+            self.simulate.phases = phases.into_iter().map(Into::into).collect();
+        }
+        self.replay.file = file.replay.file.or(self.replay.file.take());
+        if let Some(speed) = file.replay.speed {
+            self.replay.speed = speed.into_inner();
+        }
+        self.watch_config = file.watch_config.unwrap_or(self.watch_config);
+    }
+
+    fn merge_args(&mut self, args: &Args) {
+        if !args.battery.is_empty() {
+            self.battery = args.battery.clone();
+        }
+        self.adapter = args.adapter.clone().or(self.adapter.take());
+        if let Some(root) = &args.sysfs_root {
+            self.sysfs_root = root.clone();
+        }
+        self.backend = args.backend.unwrap_or(self.backend);
+        if let Some(interval) = args.polling_interval {
+            self.polling_interval = Duration::from_secs(interval);
+        }
+        if let Some(interval) = args.reconcile_interval {
+            self.reconcile_interval = Duration::from_secs(interval);
+        }
+        let levels = [args.critical, args.very_low, args.low];
+        for ((_, urgency), level) in SHORTHANDS.into_iter().zip(levels) {
+            if let Some(level) = level {
+                self.shorthand_mut(urgency).level = level;
+            }
+        }
+        if let Some(interval) = args.critical_reminder {
+            // The most urgent threshold, custom or shorthand
+            let critical = match &mut self.thresholds {
+                Some(thresholds) => thresholds
+                    .iter_mut()
+                    .max_by_key(|t| (t.urgency, Reverse(t.level))),
+                None => Some(&mut self.critical),
+            };
+            if let Some(threshold) = critical {
+                threshold.remind_interval = reminder(interval);
+            }
+        }
+        if let Some(action) = &args.action {
+            self.critical_action = Some(critical_action(action.clone()));
+        }
+        if let Some(threshold) = &mut self.critical_action {
+            threshold.level = args.action_level.unwrap_or(threshold.level);
+            if let Some(grace) = args.action_grace {
+                threshold.grace = Some(Duration::from_secs(grace));
+            }
+        }
+        if let Some(hysteresis) = args.hysteresis {
+            self.hysteresis = hysteresis.get();
+        }
+        if !args.charged.is_empty() {
+            self.charged = args.charged.iter().map(|lvl| charged(lvl.0)).collect();
+        }
+        if !args.notifier.is_empty() {
+            self.notification.sinks = args.notifier.clone();
+        }
+        self.replay.file = args.replay.clone().or(self.replay.file.take());
+        self.replay.speed = args.replay_speed.unwrap_or(self.replay.speed);
+        self.watch_config |= args.watch_config;
+    }
+
+    /// The threshold a shorthand stands for, see [`shorthand_index`]
+    fn shorthand(&self, urgency: EvPriority) -> Option<&Threshold> {
+        match &self.thresholds {
+            Some(thresholds) => shorthand_index(thresholds, urgency).map(|i| &thresholds[i]),
+            None => Some(match urgency {
+                EvPriority::Low => &self.low,
+                EvPriority::Normal => &self.very_low,
+                EvPriority::High => &self.critical,
+            }),
+        }
+    }
+
+    /// The threshold `--low`, `--very-low` or `--critical` sets,
+    /// added to the custom thresholds if none has its urgency
+    fn shorthand_mut(&mut self, urgency: EvPriority) -> &mut Threshold {
+        let shorthand = match urgency {
+            EvPriority::Low => &mut self.low,
+            EvPriority::Normal => &mut self.very_low,
+            EvPriority::High => &mut self.critical,
+        };
+        let Some(thresholds) = &mut self.thresholds else {
+            return shorthand;
+        };
+        let i = shorthand_index(thresholds, urgency).unwrap_or_else(|| {
+            thresholds.push(shorthand.clone());
+            thresholds.len() - 1
+        });
+        &mut thresholds[i]
+    }
+
+    /// The checks of the config file that CLI flags can break
+    fn validate(&self) -> Result<()> {
+        let levels = SHORTHANDS
+            .iter()
+            .filter_map(|(name, urgency)| Some((*name, self.shorthand(*urgency)?.level, ())));
+        if let Some(((), msg)) = misordered(levels) {
+            return Err(Error::Conflict(msg));
+        }
+        if let Some(thresholds) = &self.thresholds {
+            let levels: Vec<_> = thresholds
+                .iter()
+                .filter(|t| t.minutes.is_none())
+                .map(|t| t.level)
+                .collect();
+            if let Some(level) = levels
+                .iter()
+                .enumerate()
+                .find_map(|(i, l)| levels[..i].contains(l).then_some(l))
+            {
+                return Err(Error::Conflict(format!(
+                    "duplicate threshold level {level}"
+                )));
+            }
+        }
+        if self.notification.sinks.contains(&Sink::Webhook) && self.webhook.url.is_none() {
+            return Err(Error::Conflict(WEBHOOK_URL_REQUIRED.into()));
+        }
+        Ok(())
+    }
+
+    /// The thresholds in use
+    pub fn thresholds(&self) -> Vec<Threshold> {
+        let mut thresholds = match &self.thresholds {
+            Some(thresholds) => thresholds.clone(),
+            None => vec![
+                self.low.clone(),
+                self.very_low.clone(),
+                self.critical.clone(),
+            ],
+        };
+        thresholds.extend(self.critical_action.clone());
+        thresholds
+    }
+
+    pub fn priority_threshold(&self) -> PriorityThreshold {
+        PriorityThreshold::new(self.thresholds(), self.hysteresis)
+            .with_minutes_hysteresis(self.minutes_hysteresis)
+    }
+
+    pub fn charge_threshold(&self) -> ChargeThreshold {
+        ChargeThreshold::new(self.charged.clone(), self.hysteresis)
+    }
+
+    /// The battery devices to monitor, with "all" resolved
+    pub fn batteries(&self) -> io::Result<Vec<BatName>> {
+        if self.backend == Backend::Simulate && self.battery.iter().all(|b| b == "all") {
+            return Ok(vec!["BAT0".into()]);
+        }
+        // UPower combines the batteries itself
+        #[cfg(feature = "dbus")]
+        if self.backend == Backend::UPower && self.battery.iter().all(|b| b == "all") {
+            return Ok(vec![crate::batstream::upower::DISPLAY_DEVICE.into()]);
+        }
+        if self.battery.is_empty() || self.battery.iter().any(|b| b == "all") {
+            return Ok(discovery::system_batteries(&self.sysfs_root)?
+                .into_iter()
+                .map(Into::into)
+                .collect());
+        }
+        Ok(self.battery.iter().map(|b| b.as_str().into()).collect())
+    }
+
+    /// The adapter device to monitor
+    pub fn adapter(&self) -> io::Result<String> {
+        match &self.adapter {
+            Some(adapter) => Ok(adapter.clone()),
+            None if matches!(self.backend, Backend::Simulate | Backend::Replay) => Ok("AC".into()),
+            None => discovery::system_adapter(&self.sysfs_root),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    /// Config from a file and CLI flags
+    fn load(src: &str, args: &[&str]) -> Result<Config> {
+        let args = Args::parse_from([&["batmon"], args].concat());
+        let mut config = Config::default();
+        config.merge_file(ConfigFile::parse(Path::new("config.toml"), src)?);
+        config.merge_args(&args);
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[cfg(feature = "dbus")]
+    #[test]
+    fn upower_follows_the_display_device() {
+        let config = load("", &["--backend", "upower"]).unwrap();
+        assert_eq!(config.batteries().unwrap(), ["DisplayDevice".into()]);
+        let config = load("", &["--backend", "upower", "--battery", "BAT1"]).unwrap();
+        assert_eq!(config.batteries().unwrap(), ["BAT1".into()]);
+    }
+
+    #[test]
+    fn critical_reminder_keeps_custom_thresholds() {
+        let src = r#"
+            [[threshold]]
+            level = 20
+
+            [[threshold]]
+            level = 5
+            urgency = "critical"
+
+            [[threshold]]
+            level = 10
+            urgency = "critical"
+        "#;
+        let config = load(src, &["--critical-reminder", "2"]).unwrap();
+        let reminders: Vec<_> = config
+            .thresholds()
+            .iter()
+            .map(|t| (t.level.get(), t.remind_interval.map(|i| i.as_secs())))
+            .collect();
+        assert_eq!(reminders, [(20, None), (5, Some(120)), (10, None)]);
+
+        let config = load("", &["--critical-reminder", "2"]).unwrap();
+        assert_eq!(
+            config.critical.remind_interval,
+            Some(Duration::from_secs(120))
+        );
+    }
+
+    #[test]
+    fn shorthand_flags_override_the_matching_threshold() {
+        let src = r#"
+            [[threshold]]
+            level = 40
+            urgency = "low"
+
+            [[threshold]]
+            level = 20
+
+            [[threshold]]
+            level = 5
+            urgency = "critical"
+        "#;
+        let levels = |config: &Config| -> Vec<_> {
+            config
+                .thresholds()
+                .iter()
+                .map(|t| (t.level.get(), t.urgency))
+                .collect()
+        };
+        let config = load(src, &["--critical", "10"]).unwrap();
+        assert_eq!(
+            levels(&config),
+            [
+                (40, EvPriority::Low),
+                (20, EvPriority::Normal),
+                (10, EvPriority::High)
+            ]
+        );
+
+        let src = "[[threshold]]\nlevel = 20\n";
+        let config = load(src, &["--low", "35"]).unwrap();
+        assert_eq!(
+            levels(&config),
+            [(20, EvPriority::Normal), (35, EvPriority::Low)]
+        );
+    }
+
+    #[test]
+    fn merged_flags_are_validated() {
+        let cases: [(&str, &[&str], &str); 5] = [
+            (
+                "",
+                &["--low", "5", "--critical", "20"],
+                "very_low threshold must not be lower than critical threshold",
+            ),
+            (
+                "[thresholds.low]\nlevel = 40\n",
+                &["--very-low", "45"],
+                "low threshold must not be lower than very_low threshold",
+            ),
+            (
+                "[[threshold]]\nlevel = 20\n\n[[threshold]]\nlevel = 5\nurgency = \"critical\"\n",
+                &["--critical", "30"],
+                "very_low threshold must not be lower than critical threshold",
+            ),
+            (
+                "[[threshold]]\nlevel = 20\nurgency = \"low\"\n\n[[threshold]]\nlevel = 10\n",
+                &["--low", "10"],
+                "duplicate threshold level 10",
+            ),
+            (
+                "",
+                &["--notifier", "webhook"],
+                "the webhook sink requires [webhook] url",
+            ),
+        ];
+        for (src, args, msg) in cases {
+            match load(src, args) {
+                Err(Error::Conflict(got)) => assert_eq!(got, msg, "{args:?}"),
+                Err(e) => panic!("unexpected error {e} for {args:?}"),
+                Ok(_) => panic!("{args:?} should be invalid"),
+            }
+        }
+        let src = "[webhook]\nurl = \"http://localhost:8080\"\n";
+        assert!(load(src, &["--notifier", "webhook"]).is_ok());
+    }
+
+    #[test]
+    fn unknown_actions_are_rejected() {
+        let src = "[critical_action]\naction = \"hiberate\"\n";
+        let err = load(src, &[]).unwrap_err();
+        assert!(matches!(err, Error::Invalid { line: 2, .. }), "{err}");
+
+        let src = "[critical_action]\naction = { command = \"true\" }\n";
+        let config = load(src, &[]).unwrap();
+        let action = config.critical_action.unwrap().action;
+        assert_eq!(action, Some(Action::Command("true".into())));
+    }
+
+    #[test]
+    fn invalid_values_report_their_line() {
+        let cases = [
+            (
+                "[[threshold]]\nlevel = 20\n\n[[threshold]]\nlevel = 20\n",
+                5,
+                "duplicate threshold level 20",
+            ),
+            (
+                "[[threshold]]\nminutes = 10\n\n[[threshold]]\nlevel = 5\nminutes = 10\n",
+                6,
+                "duplicate threshold minutes 10",
+            ),
+            (
+                "[[threshold]]\nlevel = 20\nminutes = 0\n",
+                3,
+                "minutes must be greater than 0",
+            ),
+            (
+                "backend = \"polling\"\npolling_interval = 0\n",
+                2,
+                "polling_interval must be greater than 0",
+            ),
+            (
+                "[simulate]\nlevel = 50\ninterval = 0\n",
+                3,
+                "simulate interval must be greater than 0",
+            ),
+            (
+                "[[simulate.phase]]\nminutes = 0\nadapter = false\nrate = -1.0\n",
+                2,
+                "phase minutes must be greater than 0",
+            ),
+            (
+                "[replay]\nspeed = 0.0\n",
+                2,
+                "replay speed must be greater than 0",
+            ),
+            (
+                "[thresholds.low]\nlevel = 30\n\n[[threshold]]\nlevel = 20\n",
+                1,
+                "[thresholds.*] can't be combined with [[threshold]]",
+            ),
+            (
+                "[thresholds.low]\nlevel = 10\n\n[thresholds.very_low]\nlevel = 15\n",
+                2,
+                "low threshold must not be lower than very_low threshold",
+            ),
+            (
+                "[notification]\nsinks = [\"webhook\"]\n",
+                2,
+                "the webhook sink requires [webhook] url",
+            ),
+        ];
+        for (src, line, msg) in cases {
+            match ConfigFile::parse(Path::new("config.toml"), src) {
+                Err(Error::Invalid {
+                    line: got_line,
+                    msg: got_msg,
+                    ..
+                }) => assert_eq!((got_line, got_msg.as_str()), (line, msg), "{src}"),
+                Err(e) => panic!("unexpected error {e} for {src}"),
+                Ok(_) => panic!("{src} should be invalid"),
+            }
+        }
+    }
+}
@@ -0,0 +1,87 @@
+// Copyright 2023 developers of the `batmon` project
+// SPDX-License-Identifier: MPL-2.0
+
+//! Errors from battery event streams
+use std::io;
+
+use thiserror::Error;
+
+use crate::batstream::acpi;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("{device} does not report {property}")]
+    MissingProperty {
+        device: String,
+        property: &'static str,
+    },
+    #[error("{device} reported an invalid {property}: {value:?}")]
+    InvalidReading {
+        device: String,
+        property: &'static str,
+        value: String,
+    },
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error(transparent)]
+    Acpi(#[from] acpi::Error),
+    #[cfg(feature = "dbus")]
+    #[error("UPower error: {0}")]
+    UPower(#[from] zbus::Error),
+}
+
+impl Error {
+    /// The error only affects a single reading, the stream is still usable
+    pub fn is_bogus_reading(&self) -> bool {
+        matches!(
+            self,
+            Self::MissingProperty { .. } | Self::InvalidReading { .. }
+        )
+    }
+
+    /// The error won't go away by reopening the stream,
+    /// e.g. a missing device, denied permissions or an unsupported backend
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            Self::Io(e) | Self::Acpi(acpi::Error::Io(e)) => is_permanent_io(e),
+            // The kernel lacks the acpi_event netlink family
+            Self::Acpi(acpi::Error::Family(_) | acpi::Error::MissingIds) => true,
+            Self::Acpi(_) => false,
+            #[cfg(feature = "dbus")]
+            Self::UPower(zbus::Error::MethodError(name, ..)) => {
+                PERMANENT_DBUS_ERRORS.contains(&name.as_str())
+            }
+            #[cfg(feature = "dbus")]
+            Self::UPower(zbus::Error::FDO(e)) => matches!(
+                **e,
+                zbus::fdo::Error::ServiceUnknown(_)
+                    | zbus::fdo::Error::UnknownObject(_)
+                    | zbus::fdo::Error::UnknownInterface(_)
+                    | zbus::fdo::Error::AccessDenied(_)
+            ),
+            #[cfg(feature = "dbus")]
+            Self::UPower(zbus::Error::InputOutput(e)) => is_permanent_io(e),
+            #[cfg(feature = "dbus")]
+            Self::UPower(_) => false,
+            Self::MissingProperty { .. } | Self::InvalidReading { .. } => false,
+        }
+    }
+}
+
+fn is_permanent_io(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied | io::ErrorKind::Unsupported
+    )
+}
+
+/// D-Bus errors for a missing UPower service or device, or denied access
+#[cfg(feature = "dbus")]
+const PERMANENT_DBUS_ERRORS: [&str; 4] = [
+    "org.freedesktop.DBus.Error.ServiceUnknown",
+    "org.freedesktop.DBus.Error.UnknownObject",
+    "org.freedesktop.DBus.Error.UnknownInterface",
+    "org.freedesktop.DBus.Error.AccessDenied",
+];
+
+pub type Result<T> = std::result::Result<T, Error>;
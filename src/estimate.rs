@@ -0,0 +1,222 @@
+// Copyright 2023 developers of the `batmon` project
+// SPDX-License-Identifier: MPL-2.0
+
+//! Time-to-empty and time-to-full estimation
+use std::{collections::VecDeque, fmt, time::Duration};
+
+use crate::batstream::{BatInfo, BatName};
+
+/// Number of recent power samples averaged
+const SAMPLES: usize = 5;
+
+/// Estimated time until the battery is empty or full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Estimate {
+    Empty(Duration),
+    Full(Duration),
+}
+
+impl Estimate {
+    pub fn remaining(&self) -> Duration {
+        match *self {
+            Self::Empty(remaining) | Self::Full(remaining) => remaining,
+        }
+    }
+
+    /// Remaining time in whole minutes
+    pub fn minutes(&self) -> u64 {
+        (self.remaining().as_secs() + 30) / 60
+    }
+
+    /// Minutes until empty, `None` while charging
+    pub fn until_empty(&self) -> Option<u64> {
+        matches!(self, Self::Empty(_)).then(|| self.minutes())
+    }
+}
+
+/// Format minutes as e.g `1 hour 5 minutes`
+pub fn format_minutes(minutes: u64) -> String {
+    let plural = |n: u64, unit: &str| {
+        if n == 1 {
+            format!("1 {unit}")
+        } else {
+            format!("{n} {unit}s")
+        }
+    };
+    match (minutes / 60, minutes % 60) {
+        (0, m) => plural(m, "minute"),
+        (h, 0) => plural(h, "hour"),
+        (h, m) => format!("{} {}", plural(h, "hour"), plural(m, "minute")),
+    }
+}
+
+impl fmt::Display for Estimate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let time = format_minutes(self.minutes());
+        match self {
+            Self::Empty(_) => write!(f, "about {time} left"),
+            Self::Full(_) => write!(f, "about {time} until full"),
+        }
+    }
+}
+
+/// Energy reading of a battery, charge readings are converted with the voltage
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Reading {
+    /// µWh
+    now: u64,
+    /// µWh
+    full: u64,
+    /// µW
+    power: u64,
+}
+
+impl Reading {
+    fn new(info: &BatInfo) -> Option<Self> {
+        let power = info.power()?;
+        if let (Some(now), Some(full)) = (info.energy_now, info.energy_full) {
+            return Some(Self { now, full, power });
+        }
+        // charge (µAh) * voltage (µV) = 10^-12 Wh
+        let voltage = info.voltage?;
+        let energy = |charge: u64| charge * voltage / 1_000_000;
+        Some(Self {
+            now: energy(info.charge_now?),
+            full: energy(info.charge_full?),
+            power,
+        })
+    }
+}
+
+/// Estimates the remaining time of the monitored batteries,
+/// from the power draw averaged over recent samples
+pub struct Estimator {
+    /// Latest reading of every battery, from their details
+    batteries: Vec<(BatName, Option<Reading>)>,
+    /// Recent power samples in µW
+    samples: VecDeque<u64>,
+}
+
+impl Estimator {
+    pub fn new(batteries: &[BatName]) -> Self {
+        Self {
+            batteries: batteries.iter().map(|name| (name.clone(), None)).collect(),
+            samples: VecDeque::with_capacity(SAMPLES),
+        }
+    }
+
+    /// Keep the latest details of a battery, for the next sample
+    /// ignores batteries that aren't monitored
+    pub fn record(&mut self, battery: &str, info: &BatInfo) {
+        if let Some((_, reading)) = self.batteries.iter_mut().find(|(b, _)| &**b == battery) {
+            *reading = Reading::new(info);
+        }
+    }
+
+    /// Forget the samples, e.g when the adapter status changes
+    pub fn reset(&mut self) {
+        self.samples.clear();
+    }
+
+    /// Sample the latest readings and estimate the time until empty, or full if `charging`
+    /// `None` if a battery didn't report its energy or power
+    pub fn update(&mut self, charging: bool) -> Option<Estimate> {
+        let total = self
+            .batteries
+            .iter()
+            .try_fold(Reading::default(), |total, (_, reading)| {
+                let reading = (*reading)?;
+                Some(Reading {
+                    now: total.now + reading.now,
+                    full: total.full + reading.full,
+                    power: total.power + reading.power,
+                })
+            })?;
+        self.push(total, charging)
+    }
+
+    fn push(&mut self, reading: Reading, charging: bool) -> Option<Estimate> {
+        if self.samples.len() == SAMPLES {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(reading.power);
+
+        let power = self.samples.iter().sum::<u64>() / self.samples.len() as u64;
+        if power == 0 {
+            return None;
+        }
+        let hours = |energy: u64| Duration::from_secs_f64(energy as f64 / power as f64 * 3600.0);
+        Some(if charging {
+            Estimate::Full(hours(reading.full.saturating_sub(reading.now)))
+        } else {
+            Estimate::Empty(hours(reading.now))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(now: u64, power: u64) -> Reading {
+        Reading {
+            now,
+            full: 50_000_000,
+            power,
+        }
+    }
+
+    #[test]
+    fn power_is_averaged() {
+        let mut estimator = Estimator::new(&[]);
+        // 10 Wh left at 20 W
+        let est = estimator.push(reading(10_000_000, 20_000_000), false);
+        assert_eq!(est.unwrap().minutes(), 30);
+        // A spike to 40 W only shifts the average to 30 W
+        let est = estimator.push(reading(10_000_000, 40_000_000), false);
+        assert_eq!(est.unwrap().minutes(), 20);
+        for _ in 0..SAMPLES {
+            estimator.push(reading(10_000_000, 10_000_000), false);
+        }
+        let est = estimator.push(reading(10_000_000, 10_000_000), false);
+        assert_eq!(est, Some(Estimate::Empty(Duration::from_secs(3600))));
+    }
+
+    #[test]
+    fn time_to_full() {
+        let mut estimator = Estimator::new(&[]);
+        let est = estimator.push(reading(40_000_000, 20_000_000), true);
+        assert_eq!(est.unwrap().minutes(), 30);
+        estimator.reset();
+        assert_eq!(estimator.push(reading(40_000_000, 0), true), None);
+    }
+
+    #[test]
+    fn readings_come_from_the_details() {
+        let mut estimator = Estimator::new(&["BAT0".into(), "BAT1".into()]);
+        let info = |energy_now| BatInfo {
+            energy_now: Some(energy_now),
+            energy_full: Some(50_000_000),
+            power: Some(10_000_000),
+            ..Default::default()
+        };
+        estimator.record("BAT0", &info(5_000_000));
+        // Until every battery has reported
+        assert_eq!(estimator.update(false), None);
+        estimator.record("BAT1", &info(15_000_000));
+        estimator.record("BAT2", &info(0));
+        // 20 Wh left at 20 W
+        let est = estimator.update(false);
+        assert_eq!(est, Some(Estimate::Empty(Duration::from_secs(3600))));
+    }
+
+    #[test]
+    fn display() {
+        let est = Estimate::Empty(Duration::from_secs(22 * 60 + 10));
+        assert_eq!(est.to_string(), "about 22 minutes left");
+        let est = Estimate::Full(Duration::from_secs(65 * 60));
+        assert_eq!(est.to_string(), "about 1 hour 5 minutes until full");
+        assert_eq!(format_minutes(120), "2 hours");
+        assert_eq!(format_minutes(1), "1 minute");
+    }
+}
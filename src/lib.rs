@@ -0,0 +1,76 @@
+// Copyright 2023 developers of the `batmon` project
+// SPDX-License-Identifier: MPL-2.0
+
+//! Battery monitoring for Linux
+//!
+//! The [`batstream`] backends yield [`BatEvent`]s, [`PriorityThreshold`]s tell which
+//! threshold a battery level has reached, and [`EvConsumer`]s receive the resulting
+//! [`Notification`]s:
+//! ```no_run
+//! use batmon::{
+//!     batstream::{udev::UdevStream, BatEvent},
+//!     priority::PriorityThreshold,
+//! };
+//! use futures_lite::StreamExt;
+//!
+//! # async fn run() -> batmon::error::Result<()> {
+//! let batteries = ["BAT0".into()];
+//! let mut stream = UdevStream::new("/sys/class/power_supply".as_ref(), &batteries, "AC")?;
+//! while let Some(event) = stream.next().await {
+//!     if let BatEvent::Battery(name, lvl) = event? {
+//!         println!("{name} is at {lvl}%");
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Custom sinks read the [`Notification`] through its accessors:
+//! ```
+//! use std::convert::Infallible;
+//!
+//! use batmon::{BatEvent, EvConsumer, EvPriority, Notification};
+//!
+//! struct Urgent;
+//!
+//! impl EvConsumer for Urgent {
+//!     type Error = Infallible;
+//!
+//!     async fn consume(&self, notif: Notification) -> Result<(), Infallible> {
+//!         let BatEvent::Battery(name, _) = notif.event() else {
+//!             return Ok(());
+//!         };
+//!         if notif.priority() == EvPriority::High {
+//!             let level = notif.threshold().map(|t| t.level);
+//!             eprintln!("{name} reached {level:?}: {}", notif.body());
+//!             if let Some(estimate) = notif.estimate() {
+//!                 eprintln!("{estimate}");
+//!             }
+//!         }
+//!         Ok(())
+//!     }
+//! }
+//! ```
+
+pub mod action;
+mod aggregate;
+mod args;
+mod backoff;
+pub mod batstream;
+mod cli;
+mod config;
+pub mod error;
+pub mod estimate;
+mod monitor;
+pub mod notif;
+pub mod priority;
+mod reload;
+mod service;
+
+/// The `batmon` binary, not part of the library API
+#[doc(hidden)]
+pub use cli::run;
+
+pub use batstream::{AdapterStatus, BatEvent, BatLvl, BatName, BatStream};
+pub use notif::{DynEvConsumer, EvConsumer, Notification};
+pub use priority::{EvPriority, PriorityThreshold, Threshold};
@@ -0,0 +1,497 @@
+// Copyright 2023 developers of the `batmon` project
+// SPDX-License-Identifier: MPL-2.0
+
+//! Battery monitoring state, deciding which events are notified
+use std::{collections::HashMap, future};
+
+use tokio::time::{self, Instant};
+
+use crate::{
+    action::ActionRunner,
+    aggregate::BatAggregate,
+    batstream::{AdapterStatus, BatEvent, BatLvl, BatName, ChargeStatus},
+    config::Config,
+    estimate::{Estimate, Estimator},
+    notif::Notification,
+    priority::{ChargeThreshold, EvPriority, PriorityThreshold, Threshold},
+};
+
+/// Pending reminder for the last reached threshold
+struct Reminder {
+    /// Index of the reached threshold
+    prio: usize,
+    /// Last notified level
+    lvl: BatLvl,
+    /// Next reminder
+    at: Option<Instant>,
+}
+
+impl Reminder {
+    fn new(prio: usize, lvl: BatLvl, threshold: &Threshold) -> Option<Self> {
+        if threshold.remind_interval.is_none() && !threshold.remind_on_drop {
+            return None;
+        }
+        Some(Self {
+            prio,
+            lvl,
+            at: threshold.remind_interval.map(|i| Instant::now() + i),
+        })
+    }
+}
+
+/// Tracks the adapter, battery levels and reached thresholds,
+/// turning battery events into notifications
+pub struct Monitor {
+    threshold: PriorityThreshold,
+    charged: ChargeThreshold,
+    show_estimate: bool,
+    batteries: BatAggregate,
+    estimator: Estimator,
+    /// Last estimate, only attached to notifications if `show_estimate`
+    estimate: Option<Estimate>,
+    /// Unknown until the backend reports it, thresholds aren't evaluated until then
+    adapter: Option<AdapterStatus>,
+    /// Latest aggregated level
+    lvl: Option<BatLvl>,
+    /// Index of the last notified threshold
+    prev_bat_prio: Option<usize>,
+    /// Index of the threshold owning the pending action
+    action_prio: Option<usize>,
+    statuses: HashMap<BatName, ChargeStatus>,
+    reminder: Option<Reminder>,
+    actions: ActionRunner,
+}
+
+impl Monitor {
+    pub fn new(config: &Config, batteries: BatAggregate, estimator: Estimator) -> Self {
+        Self {
+            threshold: config.priority_threshold(),
+            charged: config.charge_threshold(),
+            show_estimate: config.notification.estimate,
+            batteries,
+            estimator,
+            estimate: None,
+            adapter: None,
+            lvl: None,
+            prev_bat_prio: None,
+            action_prio: None,
+            statuses: HashMap::new(),
+            reminder: None,
+            actions: ActionRunner::default(),
+        }
+    }
+
+    /// Apply a reloaded config
+    pub fn reconfigure(&mut self, config: &Config) {
+        self.threshold = config.priority_threshold();
+        self.charged = config.charge_threshold();
+        self.show_estimate = config.notification.estimate;
+        // The reached threshold indices refer to the old thresholds,
+        // re-derive them so the thresholds already reached aren't notified again
+        self.prev_bat_prio = self
+            .lvl
+            .filter(|_| self.adapter.is_some())
+            .and_then(|lvl| self.priority(lvl, None));
+        self.reminder = match (self.prev_bat_prio, self.lvl) {
+            (Some(prio), Some(lvl)) if !self.connected() => {
+                Reminder::new(prio, lvl, self.threshold.threshold(prio))
+            }
+            _ => None,
+        };
+        // The pending action now belongs to the most urgent reached threshold with one,
+        // cancel it if there's none left
+        if self.action_prio.is_some() {
+            self.action_prio = self
+                .prev_bat_prio
+                .and_then(|prio| (0..=prio).rev().find(|&i| self.reached(i).action.is_some()));
+            if self.action_prio.is_none() {
+                self.actions.cancel();
+            }
+        }
+    }
+
+    /// The adapter status, disconnected until it's known
+    pub fn adapter(&self) -> AdapterStatus {
+        self.adapter.unwrap_or(AdapterStatus::Disconnected)
+    }
+
+    fn connected(&self) -> bool {
+        self.adapter == Some(AdapterStatus::Connected)
+    }
+
+    /// Track the charging statuses
+    /// returns the adapter status to observe the event with,
+    /// `None` if the charging or adapter status didn't change and the event should be dropped
+    pub fn accept(&mut self, event: &BatEvent) -> Option<AdapterStatus> {
+        match event {
+            BatEvent::Status(name, info) => {
+                self.estimator.record(name, info);
+                if self.statuses.insert(name.clone(), info.status) == Some(info.status) {
+                    return None;
+                }
+            }
+            // The adapter status is tracked by `handle`, which needs the previous one
+            BatEvent::Adapter(status) if self.adapter == Some(*status) => return None,
+            BatEvent::Adapter(status) => return Some(*status),
+            BatEvent::Battery(..) => {}
+        }
+        Some(self.adapter())
+    }
+
+    fn battery_notif(&self, lvl: BatLvl, threshold: Threshold) -> Notification {
+        let event = BatEvent::Battery(self.batteries.name().clone(), lvl);
+        Notification::battery(event, threshold)
+            .with_estimate(self.estimate.filter(|_| self.show_estimate))
+    }
+
+    /// Handle an accepted event, returns the notification to send if any
+    pub fn handle(&mut self, event: BatEvent) -> Option<Notification> {
+        match event {
+            BatEvent::Battery(name, lvl) => self.handle_level(&name, lvl),
+            BatEvent::Adapter(status) => {
+                let prev = self.adapter.replace(status);
+                // The reached thresholds switch between the charge and discharge ones
+                self.actions.cancel();
+                self.action_prio = None;
+                self.prev_bat_prio = None;
+                self.reminder = None;
+                // The power draw changes direction
+                self.estimator.reset();
+                self.estimate = None;
+                if prev.is_none() {
+                    // The initial status isn't a change, evaluate the level seen so far
+                    return self.lvl.and_then(|lvl| self.evaluate(lvl));
+                }
+                Some(Notification::new(event, EvPriority::Low))
+            }
+            BatEvent::Status(..) => None,
+        }
+    }
+
+    /// Index of the threshold reached at `lvl`
+    fn priority(&self, lvl: BatLvl, prev: Option<usize>) -> Option<usize> {
+        // Charge thresholds apply while the adapter is connected
+        if self.connected() {
+            self.charged.priority(lvl, prev)
+        } else {
+            let minutes = self.estimate.and_then(|e| e.until_empty());
+            self.threshold.priority(lvl, minutes, prev)
+        }
+    }
+
+    /// Threshold at index `prio` of the thresholds for the adapter status
+    fn reached(&self, prio: usize) -> &Threshold {
+        if self.connected() {
+            self.charged.threshold(prio)
+        } else {
+            self.threshold.threshold(prio)
+        }
+    }
+
+    fn handle_level(&mut self, name: &str, lvl: BatLvl) -> Option<Notification> {
+        let lvl = self.batteries.update(name, lvl)?;
+        self.lvl = Some(lvl);
+        self.estimate = self.estimator.update(self.connected());
+        // Starting on AC at a low level mustn't run the low battery actions
+        self.adapter?;
+        self.evaluate(lvl)
+    }
+
+    /// Notify the threshold reached at `lvl`, if it's a new one
+    fn evaluate(&mut self, lvl: BatLvl) -> Option<Notification> {
+        let prio = self.priority(lvl, self.prev_bat_prio);
+        // Skip if we've already sent a notification with the same priority,
+        // unless a reminder is due for the further drop
+        if prio == self.prev_bat_prio {
+            let r = self.reminder.as_mut().filter(|r| lvl < r.lvl)?;
+            let reached = self.threshold.get(r.prio).filter(|t| t.remind_on_drop)?;
+            r.lvl = lvl;
+            return Some(self.battery_notif(lvl, reached.clone()));
+        }
+        self.prev_bat_prio = prio;
+        // Moved back off the threshold owning the pending action
+        if self.action_prio > prio {
+            self.actions.cancel();
+            self.action_prio = None;
+        }
+        let Some(prio) = prio else {
+            self.reminder = None;
+            return None;
+        };
+
+        let reached = self.reached(prio).clone();
+        if let Some(action) = &reached.action {
+            self.actions.schedule(action.clone(), reached.grace());
+            self.action_prio = Some(prio);
+        }
+        // Reminders stop once the adapter is connected
+        self.reminder = (!self.connected())
+            .then(|| Reminder::new(prio, lvl, &reached))
+            .flatten();
+        Some(self.battery_notif(lvl, reached))
+    }
+
+    /// Wait for the next reminder, forever if there's none
+    pub async fn reminder_due(&self) {
+        match self.reminder.as_ref().and_then(|r| r.at) {
+            Some(at) => time::sleep_until(at).await,
+            None => future::pending().await,
+        }
+    }
+
+    /// The reminder once it's due, and schedule the next one
+    pub fn remind(&mut self) -> Option<Notification> {
+        let r = self.reminder.as_mut()?;
+        // The thresholds may have changed since
+        let Some(reached) = self.threshold.get(r.prio) else {
+            self.reminder = None;
+            return None;
+        };
+        r.at = reached.remind_interval.map(|i| Instant::now() + i);
+        let lvl = self.lvl.unwrap_or(r.lvl);
+        Some(self.battery_notif(lvl, reached.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::Path, time::Duration};
+
+    use crate::{
+        action::{marker_action, marker_written},
+        batstream::BatInfo,
+    };
+
+    use super::*;
+
+    fn lvl(lvl: u8) -> BatLvl {
+        BatLvl::new(lvl).unwrap()
+    }
+
+    /// Monitor of a single battery, the adapter status still unknown
+    fn unknown_adapter(config: &Config) -> Monitor {
+        let batteries = ["BAT0".into()];
+        Monitor::new(
+            config,
+            BatAggregate::new(Path::new(""), &batteries),
+            Estimator::new(&[]),
+        )
+    }
+
+    /// Monitor of a single battery, starting on battery
+    fn monitor(config: &Config) -> Monitor {
+        let mut monitor = unknown_adapter(config);
+        let initial = [BatEvent::Adapter(AdapterStatus::Disconnected)];
+        assert!(feed(&mut monitor, initial).is_empty());
+        monitor
+    }
+
+    /// Feed the events, returning the notification bodies
+    fn feed(monitor: &mut Monitor, events: impl IntoIterator<Item = BatEvent>) -> Vec<String> {
+        let mut bodies = Vec::new();
+        for event in events {
+            if monitor.accept(&event).is_none() {
+                continue;
+            }
+            bodies.extend(monitor.handle(event).map(|notif| notif.body()));
+        }
+        bodies
+    }
+
+    fn levels(levels: &[u8]) -> Vec<BatEvent> {
+        levels
+            .iter()
+            .map(|&l| BatEvent::Battery("BAT0".into(), lvl(l)))
+            .collect()
+    }
+
+    fn status(status: ChargeStatus) -> BatEvent {
+        let info = BatInfo {
+            status,
+            ..Default::default()
+        };
+        BatEvent::Status("BAT0".into(), info)
+    }
+
+    #[test]
+    fn thresholds_are_notified_once() {
+        let mut monitor = monitor(&Config::default());
+        let bodies = feed(
+            &mut monitor,
+            levels(&[40, 30, 29, 31, 30, 20, 15, 16, 8, 3]),
+        );
+        assert_eq!(
+            bodies,
+            [
+                "Battery level is low at 30%",
+                "Battery level is low at 15%",
+                "Battery level is critical at 8%",
+            ]
+        );
+        // Re-armed once the level moves back past the hysteresis
+        let bodies = feed(&mut monitor, levels(&[20, 40, 30]));
+        assert_eq!(
+            bodies,
+            ["Battery level is low at 20%", "Battery level is low at 30%"]
+        );
+    }
+
+    #[test]
+    fn unchanged_statuses_are_dropped() {
+        let mut monitor = monitor(&Config::default());
+        let events = [
+            status(ChargeStatus::Discharging),
+            status(ChargeStatus::Discharging),
+            status(ChargeStatus::Charging),
+        ];
+        let accepted: Vec<_> = events.iter().map(|ev| monitor.accept(ev)).collect();
+        assert_eq!(
+            accepted,
+            [
+                Some(AdapterStatus::Disconnected),
+                None,
+                Some(AdapterStatus::Disconnected)
+            ]
+        );
+        assert!(monitor.handle(status(ChargeStatus::Full)).is_none());
+    }
+
+    #[tokio::test]
+    async fn adapter_switches_thresholds() {
+        let config = Config {
+            charged: vec![Threshold::new(lvl(80), EvPriority::Low, "", "charged")],
+            ..Default::default()
+        };
+        let mut monitor = monitor(&config);
+        let mut events = levels(&[10]);
+        events.push(BatEvent::Adapter(AdapterStatus::Connected));
+        events.extend(levels(&[50, 80, 85]));
+        events.push(BatEvent::Adapter(AdapterStatus::Disconnected));
+        events.extend(levels(&[85, 30]));
+        assert_eq!(
+            feed(&mut monitor, events),
+            [
+                "Battery level is low at 10%",
+                "AC Adapter has been connected",
+                "charged",
+                "AC Adapter has been disconnected",
+                "Battery level is low at 30%",
+            ]
+        );
+        assert_eq!(monitor.adapter(), AdapterStatus::Disconnected);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reminders_until_the_adapter_is_connected() {
+        let mut config = Config::default();
+        config.critical.remind_interval = Some(Duration::from_secs(60));
+        config.critical.remind_on_drop = true;
+        let mut monitor = monitor(&config);
+        assert_eq!(feed(&mut monitor, levels(&[8, 8, 7])).len(), 2);
+
+        let start = Instant::now();
+        monitor.reminder_due().await;
+        assert_eq!(start.elapsed(), Duration::from_secs(60));
+        let notif = monitor.remind().unwrap();
+        assert_eq!(notif.body(), "Battery level is critical at 7%");
+
+        feed(&mut monitor, [BatEvent::Adapter(AdapterStatus::Connected)]);
+        assert!(monitor.remind().is_none());
+        assert!(feed(&mut monitor, levels(&[6])).is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reminders_report_the_current_level() {
+        let mut config = Config::default();
+        config.critical.remind_interval = Some(Duration::from_secs(60));
+        let mut monitor = monitor(&config);
+        assert_eq!(feed(&mut monitor, levels(&[8, 6, 4])).len(), 1);
+
+        monitor.reminder_due().await;
+        let notif = monitor.remind().unwrap();
+        assert_eq!(notif.body(), "Battery level is critical at 4%");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconfigure_rederives_the_reached_threshold() {
+        let threshold = |level| Threshold::new(lvl(level), EvPriority::Normal, "", "{level}%");
+        let config = Config {
+            thresholds: Some(vec![threshold(20), threshold(10)]),
+            ..Default::default()
+        };
+        let mut monitor = monitor(&config);
+        assert_eq!(feed(&mut monitor, levels(&[8])), ["8%"]);
+
+        let mut config = Config {
+            thresholds: Some(vec![threshold(30), threshold(20), threshold(10)]),
+            ..Default::default()
+        };
+        config.thresholds.as_mut().unwrap()[2].remind_interval = Some(Duration::from_secs(60));
+        monitor.reconfigure(&config);
+        // Still past the same threshold
+        assert!(feed(&mut monitor, levels(&[7])).is_empty());
+        monitor.reminder_due().await;
+        assert_eq!(monitor.remind().unwrap().body(), "7%");
+    }
+
+    /// Config with a critical action at 5% creating `marker` after a minute
+    fn critical_action(marker: &Path) -> Config {
+        let mut threshold = Threshold::new(lvl(5), EvPriority::High, "", "act");
+        threshold.action = Some(marker_action(marker));
+        threshold.grace = Some(Duration::from_secs(60));
+        Config {
+            critical_action: Some(threshold),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn thresholds_wait_for_the_adapter_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("ran");
+        let config = critical_action(&marker);
+
+        // Starting on AC at a low level
+        let mut monitor = unknown_adapter(&config);
+        assert!(feed(&mut monitor, levels(&[4])).is_empty());
+        let connected = [BatEvent::Adapter(AdapterStatus::Connected)];
+        assert!(feed(&mut monitor, connected).is_empty());
+        time::sleep(Duration::from_secs(61)).await;
+        assert!(!marker_written(&marker).await);
+
+        // The level seen so far is notified once the adapter is known
+        let mut monitor = unknown_adapter(&config);
+        assert!(feed(&mut monitor, levels(&[4])).is_empty());
+        let disconnected = [BatEvent::Adapter(AdapterStatus::Disconnected)];
+        assert_eq!(feed(&mut monitor, disconnected), ["act"]);
+        time::sleep(Duration::from_secs(61)).await;
+        assert!(marker_written(&marker).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn action_is_cancelled_once_the_level_recovers() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("ran");
+        let mut monitor = monitor(&critical_action(&marker));
+        assert_eq!(feed(&mut monitor, levels(&[5])), ["act"]);
+        // Back above the action threshold, and its hysteresis, during the grace period
+        time::sleep(Duration::from_secs(30)).await;
+        assert_eq!(
+            feed(&mut monitor, levels(&[8])),
+            ["Battery level is critical at 8%"]
+        );
+        time::sleep(Duration::from_secs(60)).await;
+        assert!(!marker_written(&marker).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reconfigure_cancels_a_removed_action() {
+        let dir = tempfile::tempdir().unwrap();
+        let marker = dir.path().join("ran");
+        let mut monitor = monitor(&critical_action(&marker));
+        assert_eq!(feed(&mut monitor, levels(&[5])), ["act"]);
+        monitor.reconfigure(&Config::default());
+        time::sleep(Duration::from_secs(61)).await;
+        assert!(!marker_written(&marker).await);
+    }
+}
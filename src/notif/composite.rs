@@ -0,0 +1,291 @@
+// Copyright 2023 developers of the `batmon` project
+// SPDX-License-Identifier: MPL-2.0
+
+//! Fan-out to several sinks
+use std::{convert::Infallible, time::Duration};
+
+use log::warn;
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    time,
+};
+
+use crate::{
+    backoff::Backoff,
+    batstream::{AdapterStatus, BatEvent},
+    config::Config,
+};
+
+use super::{
+    hook::HookConsumer,
+    journal::JournalConsumer,
+    logger::{LogConsumer, StdoutConsumer},
+    webhook::WebhookConsumer,
+    DynEvConsumer, EvConsumer, Notification, Sink,
+};
+
+/// Delivery attempts per sink before giving up on a notification
+const ATTEMPTS: usize = 3;
+/// Notifications and events queued per sink before dropping new ones
+const QUEUE: usize = 16;
+
+/// Work queued for a sink
+enum Work {
+    Notify(Notification),
+    Observe(BatEvent, AdapterStatus),
+}
+
+/// A sink, delivering its notifications and events from a spawned task
+struct SinkQueue {
+    sink: Sink,
+    queue: mpsc::Sender<Work>,
+}
+
+impl SinkQueue {
+    /// Queue without waiting, dropping the work if the sink is falling behind
+    fn send(&self, work: Work) {
+        let sink = self.sink;
+        match self.queue.try_send(work) {
+            Ok(()) => {}
+            Err(TrySendError::Full(Work::Notify(_))) => {
+                warn!("{sink:?} sink is falling behind, dropping notification")
+            }
+            Err(TrySendError::Full(Work::Observe(..))) => {
+                warn!("{sink:?} sink is falling behind, dropping event")
+            }
+            Err(TrySendError::Closed(_)) => warn!("{sink:?} sink has stopped"),
+        }
+    }
+}
+
+/// Deliver the queued work in order, retrying failed notifications
+async fn deliver(sink: Sink, consumer: Box<dyn DynEvConsumer>, mut queue: mpsc::Receiver<Work>) {
+    while let Some(work) = queue.recv().await {
+        let notif = match work {
+            Work::Notify(notif) => notif,
+            Work::Observe(event, adapter) => {
+                if let Err(e) = consumer.observe_dyn(&event, adapter).await {
+                    warn!("{sink:?} sink failed to observe event: {e}");
+                }
+                continue;
+            }
+        };
+        let mut backoff = Backoff::new(Duration::from_millis(250), Duration::from_secs(1));
+        for attempt in 1..=ATTEMPTS {
+            let Err(e) = consumer.consume_dyn(notif.clone()).await else {
+                break;
+            };
+            if attempt == ATTEMPTS {
+                warn!("{sink:?} sink failed to notify, giving up: {e}");
+                break;
+            }
+            warn!("{sink:?} sink failed to notify, retrying: {e}");
+            time::sleep(backoff.next_delay()).await;
+        }
+    }
+}
+
+/// Dispatches notifications and events to every configured sink,
+/// each sink is delivered to concurrently through its queue, so a slow
+/// or failing sink is retried then logged without delaying the others
+/// or the monitor loop
+#[derive(Default)]
+pub struct Composite {
+    sinks: Vec<SinkQueue>,
+    /// Run whenever hooks are configured, independently of the sinks
+    hooks: Option<HookConsumer>,
+}
+
+impl Composite {
+    /// Build the sinks enabled in the config
+    pub fn new(config: &Config) -> Self {
+        let mut composite = Self {
+            hooks: (!config.hooks.is_empty()).then(|| HookConsumer::new(config)),
+            ..Default::default()
+        };
+        for &sink in &config.notification.sinks {
+            let consumer: Box<dyn DynEvConsumer> = match sink {
+                #[cfg(feature = "dbus")]
+                Sink::Desktop => Box::new(super::notify::NotifyConsumer::new(config)),
+                #[cfg(not(feature = "dbus"))]
+                Sink::Desktop => {
+                    warn!("built without desktop notifications, printing to stdout");
+                    Box::new(StdoutConsumer)
+                }
+                Sink::Stdout => Box::new(StdoutConsumer),
+                Sink::Journal => Box::new(JournalConsumer::new(config)),
+                Sink::Log => Box::new(LogConsumer),
+                Sink::Webhook => match WebhookConsumer::new(&config.webhook) {
+                    Ok(webhook) => Box::new(webhook),
+                    Err(e) => {
+                        warn!("webhook sink disabled: {e}");
+                        continue;
+                    }
+                },
+                Sink::None => continue,
+            };
+            composite.push(sink, consumer);
+        }
+        composite
+    }
+
+    /// Add a sink, spawning its delivery task
+    pub fn push(&mut self, sink: Sink, consumer: Box<dyn DynEvConsumer>) {
+        let (queue, rx) = mpsc::channel(QUEUE);
+        tokio::spawn(deliver(sink, consumer, rx));
+        self.sinks.push(SinkQueue { sink, queue });
+    }
+}
+
+impl EvConsumer for Composite {
+    type Error = Infallible;
+
+    async fn consume(&self, notif: Notification) -> Result<(), Infallible> {
+        if let Some(hooks) = &self.hooks {
+            let Ok(()) = hooks.consume(notif.clone()).await;
+        }
+        for sink in &self.sinks {
+            sink.send(Work::Notify(notif.clone()));
+        }
+
+        Ok(())
+    }
+
+    async fn observe(&self, event: &BatEvent, adapter: AdapterStatus) -> Result<(), Infallible> {
+        if let Some(hooks) = &self.hooks {
+            let Ok(()) = hooks.observe(event, adapter).await;
+        }
+        for sink in &self.sinks {
+            sink.send(Work::Observe(event.clone(), adapter));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io,
+        sync::{Arc, Mutex},
+    };
+
+    use crate::priority::EvPriority;
+
+    use super::*;
+
+    /// Records notifications and events after `delay`, failing if `fail` is set
+    #[derive(Clone, Default)]
+    struct MockConsumer {
+        fail: bool,
+        delay: Duration,
+        received: Arc<Mutex<Vec<String>>>,
+        observed: Arc<Mutex<Vec<AdapterStatus>>>,
+    }
+
+    impl EvConsumer for MockConsumer {
+        type Error = io::Error;
+
+        async fn consume(&self, notif: Notification) -> Result<(), io::Error> {
+            time::sleep(self.delay).await;
+            self.received.lock().unwrap().push(notif.summary());
+            if self.fail {
+                return Err(io::Error::other("sink down"));
+            }
+            Ok(())
+        }
+
+        async fn observe(
+            &self,
+            _event: &BatEvent,
+            adapter: AdapterStatus,
+        ) -> Result<(), io::Error> {
+            time::sleep(self.delay).await;
+            self.observed.lock().unwrap().push(adapter);
+            Ok(())
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failing_sink_does_not_affect_others() {
+        let failing = MockConsumer {
+            fail: true,
+            ..Default::default()
+        };
+        let working = MockConsumer::default();
+        let mut composite = Composite::default();
+        composite.push(Sink::Desktop, Box::new(failing.clone()));
+        composite.push(Sink::Log, Box::new(working.clone()));
+
+        for status in [AdapterStatus::Connected, AdapterStatus::Disconnected] {
+            let notif = Notification::new(BatEvent::Adapter(status), EvPriority::Low);
+            let Ok(()) = composite.consume(notif).await;
+        }
+        time::sleep(Duration::from_secs(10)).await;
+
+        let expected = ["AC Adapter Connected", "AC Adapter Disconnected"];
+        assert_eq!(*working.received.lock().unwrap(), expected);
+        // Retried before giving up
+        let retried: Vec<_> = expected.iter().flat_map(|s| [*s; ATTEMPTS]).collect();
+        assert_eq!(*failing.received.lock().unwrap(), retried);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_sink_does_not_delay_others() {
+        let slow = MockConsumer {
+            delay: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let working = MockConsumer::default();
+        let mut composite = Composite::default();
+        composite.push(Sink::Webhook, Box::new(slow.clone()));
+        composite.push(Sink::Desktop, Box::new(working.clone()));
+
+        let start = time::Instant::now();
+        let notif = Notification::new(BatEvent::Adapter(AdapterStatus::Connected), EvPriority::Low);
+        let Ok(()) = composite.consume(notif).await;
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(*working.received.lock().unwrap(), ["AC Adapter Connected"]);
+        assert!(slow.received.lock().unwrap().is_empty());
+        time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(*slow.received.lock().unwrap(), ["AC Adapter Connected"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn slow_sink_does_not_delay_events() {
+        let slow = MockConsumer {
+            delay: Duration::from_secs(60),
+            ..Default::default()
+        };
+        let mut composite = Composite::default();
+        composite.push(Sink::Webhook, Box::new(slow.clone()));
+
+        let start = time::Instant::now();
+        for status in [AdapterStatus::Connected, AdapterStatus::Disconnected] {
+            let Ok(()) = composite.observe(&BatEvent::Adapter(status), status).await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+
+        time::sleep(Duration::from_secs(61)).await;
+        assert_eq!(*slow.observed.lock().unwrap(), [AdapterStatus::Connected]);
+        time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(
+            *slow.observed.lock().unwrap(),
+            [AdapterStatus::Connected, AdapterStatus::Disconnected]
+        );
+    }
+
+    #[tokio::test]
+    async fn hooks_run_without_sinks() {
+        let mut config = Config::default();
+        config.notification.sinks = vec![Sink::None];
+        assert!(Composite::new(&config).hooks.is_none());
+
+        config.hooks.adapter = vec!["true".into()];
+        let composite = Composite::new(&config);
+        assert!(composite.sinks.is_empty());
+        assert!(composite.hooks.is_some());
+    }
+}
@@ -0,0 +1,203 @@
+// Copyright 2023 developers of the `batmon` project
+// SPDX-License-Identifier: MPL-2.0
+
+//! User hook scripts
+use std::{
+    convert::Infallible,
+    process::Stdio,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use log::{debug, warn};
+use tokio::{process::Command, time};
+
+use crate::{
+    batstream::{AdapterStatus, BatEvent},
+    config::{Config, HooksConfig},
+};
+
+use super::{adapter_str, priority_str, EvConsumer, Notification};
+
+/// Runs user commands on battery events
+/// the event is described through `BATMON_*` environment variables
+pub struct HookConsumer {
+    hooks: HooksConfig,
+    adapter_connected: AtomicBool,
+}
+
+impl HookConsumer {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            hooks: config.hooks.clone(),
+            adapter_connected: AtomicBool::new(false),
+        }
+    }
+
+    fn adapter(&self) -> AdapterStatus {
+        if self.adapter_connected.load(Ordering::Relaxed) {
+            AdapterStatus::Connected
+        } else {
+            AdapterStatus::Disconnected
+        }
+    }
+
+    /// Spawn the commands in the background,
+    /// killing them once they exceed the timeout
+    fn run(&self, cmds: &[String], env: &[(&str, String)]) {
+        for cmd in cmds {
+            let child = Command::new("sh")
+                .arg("-c")
+                .arg(cmd)
+                .envs(env.iter().map(|(k, v)| (k, v)))
+                .stdin(Stdio::null())
+                .kill_on_drop(true)
+                .spawn();
+            let mut child = match child {
+                Ok(child) => child,
+                Err(e) => {
+                    warn!("failed to run hook {cmd:?}: {e}");
+                    continue;
+                }
+            };
+
+            let cmd = cmd.clone();
+            let timeout = self.hooks.timeout;
+            tokio::spawn(async move {
+                match time::timeout(timeout, child.wait()).await {
+                    Ok(Ok(status)) if !status.success() => warn!("hook {cmd:?} failed: {status}"),
+                    Ok(Ok(_)) => debug!("hook {cmd:?} finished"),
+                    Ok(Err(e)) => warn!("failed to wait on hook {cmd:?}: {e}"),
+                    Err(_) => warn!("hook {cmd:?} timed out, killing it"),
+                }
+            });
+        }
+    }
+}
+
+impl EvConsumer for HookConsumer {
+    type Error = Infallible;
+
+    async fn consume(&self, notif: Notification) -> Result<(), Infallible> {
+        // adapter hooks are run on every adapter event instead
+        let BatEvent::Battery(name, lvl) = &notif.event else {
+            return Ok(());
+        };
+        let mut env = vec![
+            ("BATMON_EVENT", "threshold".into()),
+            ("BATMON_BATTERY", name.to_string()),
+            ("BATMON_LEVEL", lvl.to_string()),
+            ("BATMON_ADAPTER", adapter_str(self.adapter()).into()),
+            ("BATMON_PRIORITY", priority_str(notif.priority).into()),
+            ("BATMON_SUMMARY", notif.summary()),
+            ("BATMON_BODY", notif.body()),
+        ];
+        if let Some(estimate) = notif.estimate {
+            env.push(("BATMON_MINUTES", estimate.minutes().to_string()));
+        }
+        self.run(&self.hooks.threshold, &env);
+
+        Ok(())
+    }
+
+    async fn observe(&self, event: &BatEvent, adapter: AdapterStatus) -> Result<(), Infallible> {
+        self.adapter_connected
+            .store(adapter == AdapterStatus::Connected, Ordering::Relaxed);
+        match event {
+            BatEvent::Battery(name, lvl) => {
+                let env = [
+                    ("BATMON_EVENT", "battery".into()),
+                    ("BATMON_BATTERY", name.to_string()),
+                    ("BATMON_LEVEL", lvl.to_string()),
+                    ("BATMON_ADAPTER", adapter_str(adapter).into()),
+                ];
+                self.run(&self.hooks.battery, &env);
+            }
+            BatEvent::Adapter(status) => {
+                let env = [
+                    ("BATMON_EVENT", "adapter".into()),
+                    ("BATMON_ADAPTER", adapter_str(*status).into()),
+                ];
+                self.run(&self.hooks.adapter, &env);
+            }
+            BatEvent::Status(name, info) => {
+                let mut env = vec![
+                    ("BATMON_EVENT", "status".into()),
+                    ("BATMON_BATTERY", name.to_string()),
+                    ("BATMON_STATUS", info.status.to_string()),
+                    ("BATMON_ADAPTER", adapter_str(adapter).into()),
+                ];
+                let optional = [
+                    ("BATMON_POWER", info.power()),
+                    ("BATMON_VOLTAGE", info.voltage),
+                    ("BATMON_CYCLES", info.cycle_count),
+                    ("BATMON_HEALTH", info.health().map(u64::from)),
+                ];
+                env.extend(
+                    optional
+                        .into_iter()
+                        .filter_map(|(k, v)| Some((k, v?.to_string()))),
+                );
+                self.run(&self.hooks.status, &env);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path, time::Duration};
+
+    use tokio::time::Instant;
+
+    use crate::batstream::BatLvl;
+
+    use super::*;
+
+    fn consumer(hooks: HooksConfig) -> HookConsumer {
+        HookConsumer {
+            hooks,
+            adapter_connected: AtomicBool::new(false),
+        }
+    }
+
+    /// Wait for the hooks to write `n` lines to `path`, for up to 5s
+    async fn read_lines(path: &Path, n: usize) -> String {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            let out = fs::read_to_string(path).unwrap_or_default();
+            if out.matches('\n').count() >= n || Instant::now() > deadline {
+                return out;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn hooks_receive_event_env() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let cmd = format!(
+            "echo \"$BATMON_EVENT $BATMON_BATTERY $BATMON_LEVEL $BATMON_ADAPTER\" >> {}",
+            out.display()
+        );
+        let hooks = consumer(HooksConfig {
+            battery: vec![cmd.clone()],
+            adapter: vec![cmd],
+            threshold: Vec::new(),
+            status: Vec::new(),
+            timeout: Duration::from_secs(10),
+        });
+
+        let event = BatEvent::Battery("BAT0".into(), BatLvl::new(42).unwrap());
+        let Ok(()) = hooks.observe(&event, AdapterStatus::Disconnected).await;
+        // The hooks run concurrently, wait for the first one to keep the lines in order
+        read_lines(&out, 1).await;
+        let event = BatEvent::Adapter(AdapterStatus::Connected);
+        let Ok(()) = hooks.observe(&event, AdapterStatus::Connected).await;
+
+        let lines = read_lines(&out, 2).await;
+        assert_eq!(lines, "battery BAT0 42 disconnected\nadapter   connected\n");
+    }
+}
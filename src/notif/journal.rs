@@ -0,0 +1,113 @@
+// Copyright 2023 developers of the `batmon` project
+// SPDX-License-Identifier: MPL-2.0
+
+//! Notifications as systemd journal entries
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use tokio::net::UnixDatagram;
+
+use crate::{config::Config, priority::EvPriority};
+
+use super::{EvConsumer, Notification};
+
+/// journald native protocol socket
+const JOURNAL_SOCKET: &str = "/run/systemd/journal/socket";
+
+/// Append a field in the journal native format,
+/// values with newlines are length-prefixed
+fn append_field(buf: &mut Vec<u8>, key: &str, value: &str) {
+    buf.extend_from_slice(key.as_bytes());
+    if value.contains('\n') {
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(b'\n');
+}
+
+/// Sends notifications to journald, with syslog priorities
+pub struct JournalConsumer {
+    identifier: String,
+    socket: PathBuf,
+}
+
+impl JournalConsumer {
+    pub fn new(config: &Config) -> Self {
+        Self::with_socket(config, JOURNAL_SOCKET)
+    }
+
+    pub fn with_socket(config: &Config, socket: impl AsRef<Path>) -> Self {
+        Self {
+            identifier: config.notification.appname.clone(),
+            socket: socket.as_ref().into(),
+        }
+    }
+}
+
+impl EvConsumer for JournalConsumer {
+    type Error = io::Error;
+
+    async fn consume(&self, notif: Notification) -> Result<(), io::Error> {
+        let priority = match notif.priority {
+            EvPriority::Low => "6",
+            EvPriority::Normal => "5",
+            EvPriority::High => "2",
+        };
+        let mut buf = Vec::new();
+        append_field(&mut buf, "PRIORITY", priority);
+        append_field(&mut buf, "SYSLOG_IDENTIFIER", &self.identifier);
+        append_field(
+            &mut buf,
+            "MESSAGE",
+            &format!("{}: {}", notif.summary(), notif.body()),
+        );
+
+        let socket = UnixDatagram::unbound()?;
+        socket.send_to(&buf, &self.socket).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        batstream::{BatEvent, BatLvl},
+        priority::Threshold,
+    };
+
+    use super::*;
+
+    #[test]
+    fn multiline_values_are_length_prefixed() {
+        let mut buf = Vec::new();
+        append_field(&mut buf, "A", "b");
+        append_field(&mut buf, "C", "d\ne");
+        assert_eq!(buf, b"A=b\nC\n\x03\0\0\0\0\0\0\0d\ne\n");
+    }
+
+    #[tokio::test]
+    async fn sends_journal_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("socket");
+        let server = UnixDatagram::bind(&path).unwrap();
+
+        let journal = JournalConsumer::with_socket(&Config::default(), &path);
+        let lvl = BatLvl::new(5).unwrap();
+        let threshold = Threshold::new(lvl, EvPriority::High, "Critical", "At {level}%");
+        let notif = Notification::battery(BatEvent::Battery("BAT0".into(), lvl), threshold);
+        journal.consume(notif).await.unwrap();
+
+        let mut buf = [0; 1024];
+        let n = server.recv(&mut buf).await.unwrap();
+        assert_eq!(
+            &buf[..n],
+            b"PRIORITY=2\nSYSLOG_IDENTIFIER=batmon\nMESSAGE=Critical: At 5%\n"
+        );
+    }
+}
@@ -1,19 +1,47 @@
+// Copyright 2023 developers of the `batmon` project
+// SPDX-License-Identifier: MPL-2.0
+
 use std::convert::Infallible;
 
+use log::{info, warn};
+
+use crate::priority::EvPriority;
+
 use super::{EvConsumer, Notification};
 
+/// Prints notifications to stdout
 #[derive(Default, Clone, Copy)]
-pub struct LoggerNotifier;
+pub struct StdoutConsumer;
 
-impl EvConsumer for LoggerNotifier {
+impl EvConsumer for StdoutConsumer {
     type Error = Infallible;
 
     async fn consume(&self, notif: Notification) -> Result<(), Infallible> {
         println!(
-            "received notification event: {:?}, priority: {:?}",
-            notif.event, notif.priority,
+            "received notification event: {:?}, priority: {:?}, summary: {:?}, body: {:?}",
+            notif.event,
+            notif.priority,
+            notif.summary(),
+            notif.body(),
         );
 
         Ok(())
     }
 }
+
+/// Emits notifications as log records, high priority ones as warnings
+#[derive(Default, Clone, Copy)]
+pub struct LogConsumer;
+
+impl EvConsumer for LogConsumer {
+    type Error = Infallible;
+
+    async fn consume(&self, notif: Notification) -> Result<(), Infallible> {
+        match notif.priority {
+            EvPriority::High => warn!("{}: {}", notif.summary(), notif.body()),
+            _ => info!("{}: {}", notif.summary(), notif.body()),
+        }
+
+        Ok(())
+    }
+}
@@ -1,29 +1,218 @@
 // Copyright 2023 developers of the `batmon` project
 // SPDX-License-Identifier: MPL-2.0
 
-use std::error::Error;
+use std::{error::Error, future::Future, pin::Pin};
 
-use crate::{batstream::BatEvent, priority::EvPriority};
+use clap::ValueEnum;
+use serde::Deserialize;
 
-#[cfg(feature = "mock-notifications")]
-pub mod logger;
-#[cfg(not(feature = "mock-notifications"))]
-pub mod notify;
+use crate::{
+    batstream::{AdapterStatus, BatEvent},
+    estimate::{format_minutes, Estimate},
+    priority::{EvPriority, Threshold},
+};
 
-#[derive(Debug)]
+pub(crate) mod composite;
+pub(crate) mod hook;
+pub(crate) mod journal;
+pub(crate) mod logger;
+#[cfg(feature = "dbus")]
+pub(crate) mod notify;
+pub(crate) mod webhook;
+
+/// Notification sink
+#[derive(Clone, Copy, PartialEq, Eq, Debug, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Sink {
+    /// Desktop notifications
+    Desktop,
+    /// Plain text on stdout
+    Stdout,
+    /// systemd journal entries
+    Journal,
+    /// Log records
+    Log,
+    /// HTTP POST requests
+    Webhook,
+    /// Disable notifications
+    None,
+}
+
+fn adapter_str(status: AdapterStatus) -> &'static str {
+    match status {
+        AdapterStatus::Connected => "connected",
+        AdapterStatus::Disconnected => "disconnected",
+    }
+}
+
+fn priority_str(priority: EvPriority) -> &'static str {
+    match priority {
+        EvPriority::Low => "low",
+        EvPriority::Normal => "normal",
+        EvPriority::High => "high",
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Notification {
     event: BatEvent,
     priority: EvPriority,
+    /// The threshold reached, for battery events
+    threshold: Option<Threshold>,
+    /// Estimated time until empty, or full while charging
+    estimate: Option<Estimate>,
+}
+
+/// Substitute `{level}`, `{battery}`, `{time}`, `{action}` and `{grace}` in a template
+fn render(
+    template: &str,
+    event: &BatEvent,
+    threshold: &Threshold,
+    estimate: Option<Estimate>,
+) -> String {
+    let mut out = match event {
+        BatEvent::Battery(name, lvl) => template
+            .replace("{level}", &lvl.to_string())
+            .replace("{battery}", name),
+        BatEvent::Status(name, _) => template.replace("{battery}", name),
+        BatEvent::Adapter(_) => template.into(),
+    };
+    if out.contains("{time}") {
+        let time = match estimate {
+            Some(estimate) => format_minutes(estimate.minutes()),
+            None => "an unknown time".into(),
+        };
+        out = out.replace("{time}", &time);
+    }
+    if let Some(action) = &threshold.action {
+        out = out
+            .replace("{action}", &action.to_string())
+            .replace("{grace}", &threshold.grace().as_secs().to_string());
+    }
+    out
 }
 
 impl Notification {
     pub fn new(event: BatEvent, priority: EvPriority) -> Self {
-        Self { event, priority }
+        Self {
+            event,
+            priority,
+            threshold: None,
+            estimate: None,
+        }
+    }
+
+    pub fn battery(event: BatEvent, threshold: Threshold) -> Self {
+        Self {
+            event,
+            priority: threshold.urgency,
+            threshold: Some(threshold),
+            estimate: None,
+        }
+    }
+
+    pub fn with_estimate(mut self, estimate: Option<Estimate>) -> Self {
+        self.estimate = estimate;
+        self
+    }
+
+    /// The event notified
+    pub fn event(&self) -> &BatEvent {
+        &self.event
+    }
+
+    pub fn priority(&self) -> EvPriority {
+        self.priority
+    }
+
+    /// The threshold reached, for battery events
+    pub fn threshold(&self) -> Option<&Threshold> {
+        self.threshold.as_ref()
+    }
+
+    /// Estimated time until empty, or full while charging
+    pub fn estimate(&self) -> Option<Estimate> {
+        self.estimate
+    }
+
+    pub fn summary(&self) -> String {
+        match (&self.event, &self.threshold) {
+            (BatEvent::Adapter(AdapterStatus::Connected), _) => "AC Adapter Connected".into(),
+            (BatEvent::Adapter(AdapterStatus::Disconnected), _) => "AC Adapter Disconnected".into(),
+            (ev, Some(threshold)) => render(&threshold.summary, ev, threshold, self.estimate),
+            (_, None) => "Battery".into(),
+        }
+    }
+
+    pub fn body(&self) -> String {
+        match (&self.event, &self.threshold) {
+            (BatEvent::Adapter(AdapterStatus::Connected), _) => {
+                "AC Adapter has been connected".into()
+            }
+            (BatEvent::Adapter(AdapterStatus::Disconnected), _) => {
+                "AC Adapter has been disconnected".into()
+            }
+            (ev, Some(threshold)) => {
+                let body = render(&threshold.body, ev, threshold, self.estimate);
+                // The estimate is appended unless the template places it
+                match self.estimate {
+                    Some(estimate) if !threshold.body.contains("{time}") => {
+                        format!("{body} \u{2014} {estimate}")
+                    }
+                    _ => body,
+                }
+            }
+            (BatEvent::Battery(_, lvl), None) => format!("Battery level is at {lvl}%"),
+            (BatEvent::Status(name, info), None) => format!("{name} is {}", info.status),
+        }
     }
 }
 
-pub(crate) trait EvConsumer {
+/// Notification sink
+/// the futures are `Send` so consumers can be driven from spawned tasks
+pub trait EvConsumer {
     type Error: Error;
 
-    async fn consume(&self, notif: Notification) -> Result<(), Self::Error>;
+    fn consume(&self, notif: Notification) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Observe every battery and adapter event, notified or not
+    /// `adapter` is the adapter status after the event
+    fn observe(
+        &self,
+        _event: &BatEvent,
+        _adapter: AdapterStatus,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send {
+        async { Ok(()) }
+    }
+}
+
+pub type BoxError = Box<dyn Error + Send + Sync>;
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Object safe [`EvConsumer`], for consumers chosen at runtime
+pub trait DynEvConsumer: Send + Sync {
+    fn consume_dyn(&self, notif: Notification) -> BoxFuture<'_, Result<(), BoxError>>;
+
+    fn observe_dyn<'a>(
+        &'a self,
+        event: &'a BatEvent,
+        adapter: AdapterStatus,
+    ) -> BoxFuture<'a, Result<(), BoxError>>;
+}
+
+impl<C: EvConsumer + Send + Sync> DynEvConsumer for C
+where
+    C::Error: Send + Sync + 'static,
+{
+    fn consume_dyn(&self, notif: Notification) -> BoxFuture<'_, Result<(), BoxError>> {
+        Box::pin(async move { Ok(self.consume(notif).await?) })
+    }
+
+    fn observe_dyn<'a>(
+        &'a self,
+        event: &'a BatEvent,
+        adapter: AdapterStatus,
+    ) -> BoxFuture<'a, Result<(), BoxError>> {
+        Box::pin(async move { Ok(self.observe(event, adapter).await?) })
+    }
 }
@@ -5,48 +5,25 @@ use std::time::Duration;
 
 use notify_rust::{Notification, Urgency};
 
-use crate::{
-    batstream::{AdapterStatus, BatEvent},
-    priority::EvPriority,
-};
+use crate::{config::Config, priority::EvPriority};
 
 use super::EvConsumer;
 
-const NOTIFICATION_TIMOUT: Duration = Duration::from_secs(5);
-
 struct EvInfo {
-    summary: &'static str,
+    summary: String,
     body: String,
     urgency: Urgency,
 }
 
 impl From<super::Notification> for EvInfo {
     fn from(value: super::Notification) -> Self {
-        match (value.event, value.priority) {
-            (BatEvent::Battery(lvl), EvPriority::Low) => Self {
-                summary: "Low Battery",
-                body: format!("Battery level is low at {}%", lvl),
-                urgency: Urgency::Low,
-            },
-            (BatEvent::Battery(lvl), EvPriority::Normal) => Self {
-                summary: "Low Battery",
-                body: format!("Battery level is low at {}%", lvl),
-                urgency: Urgency::Normal,
-            },
-            (BatEvent::Battery(lvl), EvPriority::High) => Self {
-                summary: "Critical Battery",
-                body: format!("Battery level is critical at {}%", lvl),
-                urgency: Urgency::Critical,
-            },
-            (BatEvent::Adapter(AdapterStatus::Connected), _) => Self {
-                summary: "AC Adapter Connected",
-                body: "AC Adapter has been connected".into(),
-                urgency: Urgency::Low,
-            },
-            (BatEvent::Adapter(AdapterStatus::Disconnected), _) => Self {
-                summary: "AC Adapter Disconnected",
-                body: "AC Adapter has been disconnected".into(),
-                urgency: Urgency::Low,
+        Self {
+            summary: value.summary(),
+            body: value.body(),
+            urgency: match value.priority {
+                EvPriority::Low => Urgency::Low,
+                EvPriority::Normal => Urgency::Normal,
+                EvPriority::High => Urgency::Critical,
             },
         }
     }
@@ -54,11 +31,15 @@ impl From<super::Notification> for EvInfo {
 
 pub struct NotifyConsumer {
     appname: String,
+    timeout: Duration,
 }
 
 impl NotifyConsumer {
-    pub fn new(appname: String) -> Self {
-        Self { appname }
+    pub fn new(config: &Config) -> Self {
+        Self {
+            appname: config.notification.appname.clone(),
+            timeout: config.notification.timeout,
+        }
     }
 }
 
@@ -69,10 +50,10 @@ impl EvConsumer for NotifyConsumer {
         let info = EvInfo::from(notif);
         let notif = Notification::new()
             .appname(&self.appname)
-            .summary(info.summary)
+            .summary(&info.summary)
             .body(&info.body)
             .urgency(info.urgency)
-            .timeout(NOTIFICATION_TIMOUT)
+            .timeout(self.timeout)
             .finalize();
         notif.show_async().await?;
 
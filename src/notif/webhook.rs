@@ -0,0 +1,261 @@
+// Copyright 2023 developers of the `batmon` project
+// SPDX-License-Identifier: MPL-2.0
+
+//! Notifications as HTTP POST requests
+use std::{io, time::Duration};
+
+use serde::Serialize;
+use thiserror::Error;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::TcpStream,
+    time,
+};
+
+use crate::{batstream::BatEvent, config::WebhookConfig};
+
+use super::{adapter_str, priority_str, EvConsumer, Notification};
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("invalid webhook url {0:?}, expected http://host[:port][/path]")]
+    Url(String),
+    #[error("no webhook url configured")]
+    NoUrl,
+    #[error(transparent)]
+    Io(#[from] io::Error),
+    #[error("request timed out")]
+    Timeout,
+    #[error("unexpected response: {0}")]
+    Status(String),
+}
+
+/// Target of the requests, only plain HTTP is supported
+#[derive(Debug, PartialEq, Eq)]
+struct Url {
+    /// IPv6 addresses are stored without their brackets
+    host: String,
+    port: u16,
+    path: String,
+}
+
+impl Url {
+    fn parse(url: &str) -> Result<Self, Error> {
+        let invalid = || Error::Url(url.into());
+        let rest = url.strip_prefix("http://").ok_or_else(invalid)?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        // IPv6 addresses are bracketed, e.g `[::1]:8080`
+        let (host, port) = match authority.strip_prefix('[') {
+            Some(rest) => rest.split_once(']').ok_or_else(invalid)?,
+            None => match authority.find(':') {
+                Some(i) => authority.split_at(i),
+                None => (authority, ""),
+            },
+        };
+        let port = match port {
+            "" => 80,
+            port => port
+                .strip_prefix(':')
+                .and_then(|port| port.parse().ok())
+                .ok_or_else(invalid)?,
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        Ok(Self {
+            host: host.into(),
+            port,
+            path: path.into(),
+        })
+    }
+
+    /// The `Host` header value
+    fn authority(&self) -> String {
+        let host = if self.host.contains(':') {
+            format!("[{}]", self.host)
+        } else {
+            self.host.clone()
+        };
+        match self.port {
+            80 => host,
+            port => format!("{host}:{port}"),
+        }
+    }
+}
+
+/// Validate a webhook url
+pub fn check_url(url: &str) -> Result<(), Error> {
+    Url::parse(url).map(drop)
+}
+
+#[derive(Serialize)]
+struct Payload<'a> {
+    event: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    battery: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    level: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    adapter: Option<&'static str>,
+    priority: &'static str,
+    /// Estimated minutes until empty, or full while charging
+    #[serde(skip_serializing_if = "Option::is_none")]
+    minutes: Option<u64>,
+    summary: String,
+    body: String,
+}
+
+impl<'a> From<&'a Notification> for Payload<'a> {
+    fn from(notif: &'a Notification) -> Self {
+        let (event, battery, level, adapter) = match &notif.event {
+            BatEvent::Battery(name, lvl) => ("battery", Some(&**name), Some(lvl.get()), None),
+            BatEvent::Adapter(status) => ("adapter", None, None, Some(adapter_str(*status))),
+            BatEvent::Status(name, _) => ("status", Some(&**name), None, None),
+        };
+        Self {
+            event,
+            battery,
+            level,
+            adapter,
+            priority: priority_str(notif.priority),
+            minutes: notif.estimate.map(|e| e.minutes()),
+            summary: notif.summary(),
+            body: notif.body(),
+        }
+    }
+}
+
+/// POSTs notifications as JSON
+pub struct WebhookConsumer {
+    url: Url,
+    timeout: Duration,
+}
+
+impl WebhookConsumer {
+    pub fn new(config: &WebhookConfig) -> Result<Self, Error> {
+        let url = config.url.as_deref().ok_or(Error::NoUrl)?;
+        Ok(Self {
+            url: Url::parse(url)?,
+            timeout: config.timeout,
+        })
+    }
+
+    async fn post(&self, body: &[u8]) -> Result<(), Error> {
+        let Url { host, port, path } = &self.url;
+        let mut stream = TcpStream::connect((host.as_str(), *port)).await?;
+        let head = format!(
+            "POST {path} HTTP/1.1\r\n\
+             Host: {}\r\n\
+             Content-Type: application/json\r\n\
+             Content-Length: {}\r\n\
+             Connection: close\r\n\r\n",
+            self.url.authority(),
+            body.len()
+        );
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(body).await?;
+
+        let mut status = String::new();
+        BufReader::new(stream).read_line(&mut status).await?;
+        let status = status.trim_end();
+        match status.split(' ').nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => Err(Error::Status(status.into())),
+        }
+    }
+}
+
+impl EvConsumer for WebhookConsumer {
+    type Error = Error;
+
+    async fn consume(&self, notif: Notification) -> Result<(), Error> {
+        let body = serde_json::to_vec(&Payload::from(&notif)).unwrap();
+        time::timeout(self.timeout, self.post(&body))
+            .await
+            .map_err(|_| Error::Timeout)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{io::AsyncReadExt, net::TcpListener};
+
+    use crate::{batstream::AdapterStatus, priority::EvPriority};
+
+    use super::*;
+
+    #[test]
+    fn parse_url() {
+        assert_eq!(
+            Url::parse("http://localhost:8080/battery").unwrap(),
+            Url {
+                host: "localhost".into(),
+                port: 8080,
+                path: "/battery".into()
+            }
+        );
+        assert_eq!(Url::parse("http://example.com").unwrap().port, 80);
+        assert!(Url::parse("https://example.com").is_err());
+        assert!(Url::parse("http://:80/").is_err());
+        assert!(Url::parse("http://example.com:/").is_err());
+    }
+
+    #[test]
+    fn parse_ipv6_url() {
+        let url = Url::parse("http://[::1]:8080/battery").unwrap();
+        assert_eq!(
+            url,
+            Url {
+                host: "::1".into(),
+                port: 8080,
+                path: "/battery".into()
+            }
+        );
+        assert_eq!(url.authority(), "[::1]:8080");
+        let url = Url::parse("http://[::1]/").unwrap();
+        assert_eq!((url.host.as_str(), url.port), ("::1", 80));
+        assert_eq!(url.authority(), "[::1]");
+        assert!(Url::parse("http://::1/").is_err());
+        assert!(Url::parse("http://[::1/").is_err());
+        assert!(Url::parse("http://[::1]x/").is_err());
+        assert!(Url::parse("http://[]/").is_err());
+    }
+
+    #[tokio::test]
+    async fn posts_json_payload() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut req = Vec::new();
+            let mut buf = [0; 1024];
+            while !req.ends_with(b"}") {
+                let n = conn.read(&mut buf).await.unwrap();
+                req.extend_from_slice(&buf[..n]);
+            }
+            conn.write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .await
+                .unwrap();
+            String::from_utf8(req).unwrap()
+        });
+
+        let webhook = WebhookConsumer::new(&WebhookConfig {
+            url: Some(format!("http://127.0.0.1:{port}/hook")),
+            timeout: Duration::from_secs(5),
+        })
+        .unwrap();
+        let notif = Notification::new(BatEvent::Adapter(AdapterStatus::Connected), EvPriority::Low);
+        webhook.consume(notif).await.unwrap();
+
+        let req = server.await.unwrap();
+        assert!(req.starts_with(&format!(
+            "POST /hook HTTP/1.1\r\nHost: 127.0.0.1:{port}\r\n"
+        )));
+        assert!(req.ends_with(
+            r#"{"event":"adapter","adapter":"connected","priority":"low","summary":"AC Adapter Connected","body":"AC Adapter has been connected"}"#
+        ));
+    }
+}
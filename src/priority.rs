@@ -1,37 +1,334 @@
 // Copyright 2023 developers of the `batmon` project
 // SPDX-License-Identifier: MPL-2.0
 
-use crate::batstream::BatLvl;
+use std::{cmp::Reverse, str::FromStr, time::Duration};
+
+use serde::{de, Deserialize, Deserializer};
+
+use crate::{action::Action, batstream::BatLvl};
 
 /// Event Priority
-#[derive(Clone, Copy, PartialEq, PartialOrd, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum EvPriority {
     Low,
     Normal,
+    #[serde(alias = "critical")]
     High,
 }
 
-/// Event Priority Threshold
+/// Battery level threshold
+#[derive(Debug, Clone)]
+pub struct Threshold {
+    pub level: BatLvl,
+    /// Also reached when the estimated minutes until empty drop to this
+    pub minutes: Option<u64>,
+    pub urgency: EvPriority,
+    /// Notification summary
+    pub summary: String,
+    /// Notification body, `{level}` and `{battery}` are substituted
+    pub body: String,
+    /// Action to run when the threshold is reached
+    pub action: Option<Action>,
+    /// Delay before running the action, cancelled if the adapter is connected
+    pub grace: Option<Duration>,
+    /// Repeat the notification at this interval, until the adapter is connected
+    pub remind_interval: Option<Duration>,
+    /// Repeat the notification on every further drop in level
+    pub remind_on_drop: bool,
+}
+
+impl Threshold {
+    pub fn new(level: BatLvl, urgency: EvPriority, summary: &str, body: &str) -> Self {
+        Self {
+            level,
+            minutes: None,
+            urgency,
+            summary: summary.into(),
+            body: body.into(),
+            action: None,
+            grace: None,
+            remind_interval: None,
+            remind_on_drop: false,
+        }
+    }
+
+    /// Delay before running the action
+    pub fn grace(&self) -> Duration {
+        match (&self.action, self.grace) {
+            (_, Some(grace)) => grace,
+            (Some(action), None) => action.default_grace(),
+            (None, None) => Duration::ZERO,
+        }
+    }
+}
+
+/// Event Priority Thresholds
 pub struct PriorityThreshold {
-    /// Triggers Low Priorirty Event
-    pub low: BatLvl,
-    /// Triggers Normal Priority Event
-    pub normal: BatLvl,
-    /// Triggers High Priority Event
-    pub high: BatLvl,
+    /// Sorted by descending level, then minutes
+    thresholds: Vec<Threshold>,
+    /// How far the level must rise above a reached threshold to re-arm it
+    hysteresis: u8,
+    /// How far the estimate must rise above a reached threshold to re-arm it
+    minutes_hysteresis: u64,
 }
 
 impl PriorityThreshold {
-    pub fn priority(&self, lvl: BatLvl) -> Option<EvPriority> {
-        if lvl <= self.high {
-            return Some(EvPriority::High);
+    pub fn new(mut thresholds: Vec<Threshold>, hysteresis: u8) -> Self {
+        thresholds.sort_by_key(|t| (Reverse(t.level), Reverse(t.minutes)));
+        Self {
+            thresholds,
+            hysteresis,
+            minutes_hysteresis: 0,
+        }
+    }
+
+    pub fn with_minutes_hysteresis(mut self, minutes: u64) -> Self {
+        self.minutes_hysteresis = minutes;
+        self
+    }
+
+    /// Index of the most urgent of the lowest threshold the level has reached
+    /// and the time thresholds the estimated `minutes` until empty have reached
+    /// level thresholds up to `prev` stay reached until the level rises past the hysteresis,
+    /// a time threshold at `prev` until the estimate does, an unknown estimate doesn't re-arm it
+    pub fn priority(
+        &self,
+        lvl: BatLvl,
+        minutes: Option<u64>,
+        prev: Option<usize>,
+    ) -> Option<usize> {
+        let by_level = self.thresholds.iter().enumerate().rposition(|(i, t)| {
+            lvl <= t.level
+                || prev.is_some_and(|prev| i <= prev)
+                    && lvl.get() <= t.level.get().saturating_add(self.hysteresis)
+        });
+        let by_time = self
+            .thresholds
+            .iter()
+            .enumerate()
+            .filter(|&(i, t)| {
+                let Some(t) = t.minutes else {
+                    return false;
+                };
+                let reached = minutes.is_some_and(|m| m <= t);
+                let held = prev == Some(i)
+                    && minutes.is_none_or(|m| m <= t.saturating_add(self.minutes_hysteresis));
+                reached || held
+            })
+            .map(|(i, _)| i);
+        // Level and time thresholds can't be compared, the urgency orders them
+        by_level
+            .into_iter()
+            .chain(by_time)
+            .max_by_key(|&i| (self.thresholds[i].urgency, i))
+    }
+
+    pub fn threshold(&self, idx: usize) -> &Threshold {
+        &self.thresholds[idx]
+    }
+
+    pub fn get(&self, idx: usize) -> Option<&Threshold> {
+        self.thresholds.get(idx)
+    }
+}
+
+/// Charge level, either a percentage or "full"
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ChargeLvl(pub BatLvl);
+
+impl FromStr for ChargeLvl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "full" {
+            return Ok(Self(BatLvl::MAX));
+        }
+        s.parse()
+            .map(Self)
+            .map_err(|_| format!("expected a level between 0 and 100 or \"full\", got {s:?}"))
+    }
+}
+
+impl<'de> Deserialize<'de> for ChargeLvl {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Level(BatLvl),
+            Named(String),
         }
-        if lvl <= self.normal {
-            return Some(EvPriority::Normal);
+
+        match Raw::deserialize(deserializer)? {
+            Raw::Level(lvl) => Ok(Self(lvl)),
+            Raw::Named(s) => s.parse().map_err(de::Error::custom),
         }
-        if lvl <= self.low {
-            return Some(EvPriority::Low);
+    }
+}
+
+/// Charge level thresholds, only considered while the adapter is connected
+pub struct ChargeThreshold {
+    /// Sorted by ascending level
+    thresholds: Vec<Threshold>,
+    /// How far the level must drop below a reached threshold to re-arm it
+    hysteresis: u8,
+}
+
+impl ChargeThreshold {
+    pub fn new(mut thresholds: Vec<Threshold>, hysteresis: u8) -> Self {
+        thresholds.sort_by_key(|t| t.level);
+        Self {
+            thresholds,
+            hysteresis,
         }
-        None
+    }
+
+    /// Index of the highest threshold the level has reached
+    /// thresholds up to `prev` stay reached until the level drops past the hysteresis
+    pub fn priority(&self, lvl: BatLvl, prev: Option<usize>) -> Option<usize> {
+        self.thresholds.iter().enumerate().rposition(|(i, t)| {
+            lvl >= t.level
+                || prev.is_some_and(|prev| i <= prev)
+                    && lvl.get() >= t.level.get().saturating_sub(self.hysteresis)
+        })
+    }
+
+    pub fn threshold(&self, idx: usize) -> &Threshold {
+        &self.thresholds[idx]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lvl(lvl: u8) -> BatLvl {
+        BatLvl::new(lvl).unwrap()
+    }
+
+    fn thresholds(levels: &[u8]) -> Vec<Threshold> {
+        levels
+            .iter()
+            .map(|&l| Threshold::new(lvl(l), EvPriority::Normal, "", ""))
+            .collect()
+    }
+
+    /// Levels of the thresholds notified for a sequence of battery levels,
+    /// deduplicated like `stream_loop`
+    fn notified(
+        priority: impl Fn(BatLvl, Option<usize>) -> Option<usize>,
+        threshold: impl Fn(usize) -> BatLvl,
+        levels: &[u8],
+    ) -> Vec<u8> {
+        let mut prev = None;
+        let mut out = Vec::new();
+        for &l in levels {
+            let prio = priority(lvl(l), prev);
+            if prio == prev {
+                continue;
+            }
+            prev = prio;
+            out.extend(prio.map(|p| threshold(p).get()));
+        }
+        out
+    }
+
+    fn discharge(t: &PriorityThreshold, levels: &[u8]) -> Vec<u8> {
+        notified(
+            |l, p| t.priority(l, None, p),
+            |p| t.threshold(p).level,
+            levels,
+        )
+    }
+
+    fn charge(t: &ChargeThreshold, levels: &[u8]) -> Vec<u8> {
+        notified(|l, p| t.priority(l, p), |p| t.threshold(p).level, levels)
+    }
+
+    #[test]
+    fn priority_picks_lowest_reached_threshold() {
+        let t = PriorityThreshold::new(thresholds(&[15, 30, 8]), 0);
+        assert_eq!(t.priority(lvl(50), None, None), None);
+        assert_eq!(
+            t.threshold(t.priority(lvl(30), None, None).unwrap()).level,
+            lvl(30)
+        );
+        assert_eq!(
+            t.threshold(t.priority(lvl(16), None, None).unwrap()).level,
+            lvl(30)
+        );
+        assert_eq!(
+            t.threshold(t.priority(lvl(15), None, None).unwrap()).level,
+            lvl(15)
+        );
+        assert_eq!(
+            t.threshold(t.priority(lvl(0), None, None).unwrap()).level,
+            lvl(8)
+        );
+    }
+
+    #[test]
+    fn oscillation_refires_without_hysteresis() {
+        let t = PriorityThreshold::new(thresholds(&[30]), 0);
+        assert_eq!(discharge(&t, &[31, 30, 31, 30, 31, 30]), [30, 30, 30]);
+    }
+
+    #[test]
+    fn oscillation_is_absorbed_by_hysteresis() {
+        let t = PriorityThreshold::new(thresholds(&[30, 15]), 2);
+        assert_eq!(discharge(&t, &[31, 30, 31, 30, 32, 30, 29]), [30]);
+        assert_eq!(discharge(&t, &[16, 15, 16, 17, 15, 14]), [30, 15]);
+    }
+
+    #[test]
+    fn hysteresis_rearms_above_threshold() {
+        let t = PriorityThreshold::new(thresholds(&[30, 15]), 2);
+        assert_eq!(discharge(&t, &[30, 33, 30]), [30, 30]);
+        // rising past the lower threshold only re-arms that one
+        assert_eq!(discharge(&t, &[15, 18, 15]), [15, 30, 15]);
+        assert_eq!(discharge(&t, &[15, 17, 15]), [15]);
+    }
+
+    #[test]
+    fn lower_threshold_fires_despite_hysteresis() {
+        let t = PriorityThreshold::new(thresholds(&[30, 15, 8]), 5);
+        assert_eq!(discharge(&t, &[30, 34, 20, 15, 19, 8, 12]), [30, 15, 8]);
+    }
+
+    #[test]
+    fn time_thresholds_mix_with_levels() {
+        let low = Threshold::new(lvl(15), EvPriority::Low, "", "");
+        let mut soon = Threshold::new(BatLvl::MIN, EvPriority::Normal, "", "");
+        soon.minutes = Some(20);
+        let t = PriorityThreshold::new(vec![soon, low], 0).with_minutes_hysteresis(5);
+        let minutes = |prio: Option<usize>| prio.map(|p| t.threshold(p).minutes);
+
+        // Heavy load at 40%
+        let prio = t.priority(lvl(40), Some(18), None);
+        assert_eq!(minutes(prio), Some(Some(20)));
+        // Idle at 15%
+        assert_eq!(minutes(t.priority(lvl(15), Some(90), None)), Some(None));
+
+        assert_eq!(t.priority(lvl(39), Some(24), prio), prio);
+        assert_eq!(t.priority(lvl(39), None, prio), prio);
+        assert_eq!(t.priority(lvl(39), Some(26), prio), None);
+    }
+
+    #[test]
+    fn lower_levels_fire_despite_lower_urgency() {
+        let high = Threshold::new(lvl(50), EvPriority::Normal, "", "");
+        let low = Threshold::new(lvl(20), EvPriority::Low, "", "");
+        let t = PriorityThreshold::new(vec![low, high], 0);
+        assert_eq!(discharge(&t, &[60, 50, 30, 20, 15]), [50, 20]);
+    }
+
+    #[test]
+    fn charge_oscillation_is_absorbed_by_hysteresis() {
+        let t = ChargeThreshold::new(thresholds(&[80, 100]), 0);
+        assert_eq!(charge(&t, &[79, 80, 79, 80]), [80, 80]);
+
+        let t = ChargeThreshold::new(thresholds(&[80, 100]), 2);
+        assert_eq!(charge(&t, &[79, 80, 79, 80, 81, 100, 99]), [80, 100]);
+        assert_eq!(charge(&t, &[80, 77, 80]), [80, 80]);
     }
 }
@@ -0,0 +1,91 @@
+// Copyright 2023 developers of the `batmon` project
+// SPDX-License-Identifier: MPL-2.0
+
+//! Configuration hot-reloading
+use std::{io, path::Path, pin::Pin};
+
+use futures_lite::{stream, Stream, StreamExt};
+use inotify::{Inotify, WatchMask};
+use log::{error, info, warn};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+
+use crate::{args::Args, config::Config};
+
+type Changes = Pin<Box<dyn Stream<Item = ()> + Send>>;
+
+/// Stream of modifications to the file at `path`
+/// the parent directory is watched, as editors usually replace the file
+fn file_changes(path: &Path) -> io::Result<Changes> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+    let file_name = path.file_name().map(ToOwned::to_owned);
+    let inotify = Inotify::init()?;
+    inotify.watches().add(
+        dir,
+        WatchMask::CLOSE_WRITE | WatchMask::MOVED_TO | WatchMask::CREATE,
+    )?;
+
+    let changes = inotify
+        .into_event_stream([0; 1024])?
+        .filter_map(move |ev| match ev {
+            Ok(ev) if ev.name == file_name => Some(()),
+            Ok(_) => None,
+            Err(e) => {
+                warn!("failed to watch config file: {e}");
+                None
+            }
+        });
+    Ok(Box::pin(changes))
+}
+
+fn reload(args: &Args, tx: &watch::Sender<Config>) {
+    let config = match Config::load(args) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("failed to reload config, keeping the current one: {e}");
+            return;
+        }
+    };
+
+    let needs_restart = {
+        let current = tx.borrow();
+        config.battery != current.battery
+            || config.adapter != current.adapter
+            || config.sysfs_root != current.sysfs_root
+            || config.backend != current.backend
+            || config.polling_interval != current.polling_interval
+            || config.reconcile_interval != current.reconcile_interval
+            || config.simulate != current.simulate
+            || config.replay.file != current.replay.file
+            || config.replay.speed != current.replay.speed
+    };
+    if needs_restart {
+        warn!("device and backend changes only take effect after a restart");
+    }
+    tx.send_replace(config);
+}
+
+/// Reload the config on SIGHUP, and on file changes if `watch_config` is set
+pub fn spawn_reloader(args: Args, config: Config) -> io::Result<watch::Receiver<Config>> {
+    let mut hangup = signal(SignalKind::hangup())?;
+    let mut changes: Changes = match Config::path(&args) {
+        Some(path) if config.watch_config => file_changes(&path)?,
+        _ => Box::pin(stream::pending()),
+    };
+
+    let (tx, rx) = watch::channel(config);
+    tokio::spawn(async move {
+        loop {
+            tokio::select! {
+                Some(()) = hangup.recv() => info!("received SIGHUP, reloading config"),
+                Some(()) = changes.next() => info!("config file changed, reloading"),
+                else => break,
+            }
+            reload(&args, &tx);
+        }
+    });
+
+    Ok(rx)
+}
@@ -0,0 +1,494 @@
+// Copyright 2023 developers of the `batmon` project
+// SPDX-License-Identifier: MPL-2.0
+
+//! The event loop tying a backend stream, the monitor and the consumers together
+use std::{path::PathBuf, time::Duration};
+
+use futures_lite::StreamExt;
+use log::{info, warn};
+use tokio::{sync::watch, time};
+
+#[cfg(feature = "dbus")]
+use crate::batstream::upower::UPowerStream;
+use crate::{
+    args::Backend,
+    backoff::Backoff,
+    batstream::{
+        acpi::AcpiStream,
+        auto::auto_stream,
+        hybrid::hybrid_stream,
+        polling::polling_stream,
+        simulate::{simulate_stream, Profile},
+        trace::{replay_stream, Recorder, Trace},
+        udev::UdevStream,
+        BatName, BatStream,
+    },
+    config::Config,
+    monitor::Monitor,
+    notif::{EvConsumer, Notification},
+};
+
+/// Opens backend streams, to re-establish them after errors
+pub struct StreamSource {
+    pub backend: Backend,
+    pub sysfs_root: PathBuf,
+    pub polling_interval: Duration,
+    pub reconcile_interval: Duration,
+    pub batteries: Vec<BatName>,
+    pub adapter: String,
+    pub simulate: Profile,
+    pub replay: Trace,
+    pub replay_speed: f64,
+    /// Records the events of every opened stream
+    pub recorder: Option<Recorder>,
+}
+
+impl StreamSource {
+    pub async fn open(&self) -> crate::error::Result<BatStream<'_>> {
+        let root = &self.sysfs_root;
+        let stream: BatStream = match self.backend {
+            Backend::Polling => Box::pin(
+                polling_stream(root, self.polling_interval, &self.batteries, &self.adapter).await?,
+            ),
+            Backend::Udev => Box::pin(UdevStream::new(root, &self.batteries, &self.adapter)?),
+            Backend::Acpi => Box::pin(AcpiStream::new(root, &self.batteries, &self.adapter).await?),
+            Backend::Hybrid => {
+                hybrid_stream(
+                    root,
+                    self.reconcile_interval,
+                    &self.batteries,
+                    &self.adapter,
+                )
+                .await?
+            }
+            #[cfg(feature = "dbus")]
+            Backend::UPower => Box::pin(UPowerStream::new(&self.batteries).await?),
+            Backend::Simulate => Box::pin(simulate_stream(
+                self.simulate.clone(),
+                self.batteries.clone(),
+            )),
+            Backend::Replay => Box::pin(replay_stream(self.replay.clone(), self.replay_speed)),
+            Backend::Auto => Box::pin(auto_stream(
+                root.clone(),
+                self.polling_interval,
+                self.batteries.clone(),
+                self.adapter.clone(),
+            )),
+        };
+        Ok(match &self.recorder {
+            Some(recorder) => recorder.record(stream),
+            None => stream,
+        })
+    }
+
+    /// Open the stream again after `delay`
+    async fn reopen(&self, delay: Duration) -> crate::error::Result<BatStream<'_>> {
+        time::sleep(delay).await;
+        self.open().await
+    }
+}
+
+/// Send a notification, failures are logged
+async fn notify<C: EvConsumer>(consumer: &C, notif: Notification) {
+    if let Err(e) = consumer.consume(notif).await {
+        warn!("failed to send notification: {e}");
+    }
+}
+
+/// Handle battery events
+/// the monitor and consumer are reconfigured whenever the config changes,
+/// the stream is reopened from `source` on errors, retrying with backoff
+/// while still handling reminders and config changes,
+/// permanent errors are returned instead
+pub async fn stream_loop<C: EvConsumer>(
+    source: &StreamSource,
+    mut stream: BatStream<'_>,
+    mut config: watch::Receiver<Config>,
+    make_consumer: impl Fn(&Config) -> C,
+    mut monitor: Monitor,
+) -> crate::error::Result<()> {
+    let mut backoff = Backoff::new(Duration::from_secs(1), Duration::from_secs(60));
+    let mut consumer = make_consumer(&config.borrow());
+    let reopen = |backoff: &mut Backoff| Some(Box::pin(source.reopen(backoff.next_delay())));
+    let mut reopening = None;
+    loop {
+        let event = tokio::select! {
+            event = stream.next(), if reopening.is_none() => event,
+            opened = async { reopening.as_mut().unwrap().await }, if reopening.is_some() => {
+                reopening = None;
+                match opened {
+                    Ok(opened) => stream = opened,
+                    Err(e) if crate::error::Error::is_permanent(&e) => return Err(e),
+                    Err(e) => {
+                        warn!("failed to reopen {:?} backend: {e}", source.backend);
+                        reopening = reopen(&mut backoff);
+                    }
+                }
+                continue;
+            }
+            Ok(()) = config.changed() => {
+                let config = config.borrow_and_update();
+                monitor.reconfigure(&config);
+                consumer = make_consumer(&config);
+                continue;
+            }
+            () = monitor.reminder_due() => {
+                if let Some(notif) = monitor.remind() {
+                    notify(&consumer, notif).await;
+                }
+                continue;
+            }
+        };
+        let event = match event {
+            Some(Ok(event)) => {
+                backoff.reset();
+                event
+            }
+            Some(Err(e)) if e.is_bogus_reading() => {
+                warn!("skipping reading: {e}");
+                continue;
+            }
+            Some(Err(e)) if e.is_permanent() => return Err(e),
+            Some(Err(e)) => {
+                warn!("{:?} backend error, reopening it: {e}", source.backend);
+                reopening = reopen(&mut backoff);
+                continue;
+            }
+            None if source.backend == Backend::Replay => {
+                info!("trace replayed");
+                return Ok(());
+            }
+            None if source.backend == Backend::Simulate => {
+                info!("simulation ended, replaying it");
+                reopening = reopen(&mut backoff);
+                continue;
+            }
+            None => {
+                warn!("{:?} backend stream ended, reopening it", source.backend);
+                reopening = reopen(&mut backoff);
+                continue;
+            }
+        };
+        let Some(adapter) = monitor.accept(&event) else {
+            continue;
+        };
+        if let Err(e) = consumer.observe(&event, adapter).await {
+            warn!("failed to observe event: {e}");
+        }
+        if let Some(notif) = monitor.handle(event) {
+            notify(&consumer, notif).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        fs,
+        path::Path,
+        sync::{Arc, Mutex},
+    };
+
+    use crate::{
+        action::{marker_action, marker_written},
+        aggregate::BatAggregate,
+        batstream::{simulate::Phase, sysfs::FakeSysfs, AdapterStatus, BatEvent, BatLvl},
+        estimate::Estimator,
+        priority::{EvPriority, Threshold},
+    };
+
+    use super::*;
+
+    /// Records the notification bodies and observed events
+    #[derive(Clone, Default)]
+    struct MockConsumer {
+        bodies: Arc<Mutex<Vec<String>>>,
+        observed: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl EvConsumer for MockConsumer {
+        type Error = Infallible;
+
+        async fn consume(&self, notif: Notification) -> Result<(), Infallible> {
+            self.bodies.lock().unwrap().push(notif.body());
+            Ok(())
+        }
+
+        async fn observe(
+            &self,
+            event: &BatEvent,
+            adapter: AdapterStatus,
+        ) -> Result<(), Infallible> {
+            let event = match event {
+                BatEvent::Battery(name, lvl) => format!("{name} {lvl}"),
+                BatEvent::Adapter(status) => format!("{status:?}"),
+                BatEvent::Status(name, info) => format!("{name} {}", info.status),
+            };
+            self.observed
+                .lock()
+                .unwrap()
+                .push(format!("{event} ({adapter:?})"));
+            Ok(())
+        }
+    }
+
+    fn lvl(lvl: u8) -> BatLvl {
+        BatLvl::new(lvl).unwrap()
+    }
+
+    /// Run `stream_loop` on the simulate backend for `duration` of real time
+    async fn simulate(config: Config, duration: Duration) -> Vec<String> {
+        let consumer = MockConsumer::default();
+        let run = run(
+            Backend::Simulate,
+            Trace::default(),
+            config,
+            consumer.clone(),
+        );
+        // The loop only returns on permanent errors
+        time::timeout(duration, run).await.unwrap_err();
+        let bodies = consumer.bodies.lock().unwrap().clone();
+        bodies
+    }
+
+    fn source(backend: Backend, replay: Trace, config: &Config) -> StreamSource {
+        StreamSource {
+            backend,
+            sysfs_root: PathBuf::new(),
+            polling_interval: config.polling_interval,
+            reconcile_interval: config.reconcile_interval,
+            batteries: vec!["BAT0".into()],
+            adapter: "AC".into(),
+            simulate: config.simulate.clone(),
+            replay,
+            replay_speed: 1.0,
+            recorder: None,
+        }
+    }
+
+    fn monitor(config: &Config) -> Monitor {
+        let aggregate = BatAggregate::new(Path::new(""), &["BAT0".into()]);
+        Monitor::new(config, aggregate, Estimator::new(&[]))
+    }
+
+    async fn run(backend: Backend, replay: Trace, config: Config, consumer: MockConsumer) {
+        let source = source(backend, replay, &config);
+        let stream = source.open().await.unwrap();
+        let monitor = monitor(&config);
+        let (_tx, config) = watch::channel(config);
+        stream_loop(&source, stream, config, move |_| consumer.clone(), monitor)
+            .await
+            .unwrap();
+    }
+
+    /// Replay a trace through `stream_loop` until its end
+    async fn replay(trace: &str) -> MockConsumer {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), trace).unwrap();
+        let trace = Trace::load(file.path()).unwrap();
+
+        let consumer = MockConsumer::default();
+        run(Backend::Replay, trace, Config::default(), consumer.clone()).await;
+        consumer
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replayed_oscillation_is_notified_once() {
+        let mut trace = String::from("0.000 adapter disconnected\n");
+        for (i, lvl) in [32, 30, 31, 30, 31, 29, 30].into_iter().enumerate() {
+            trace += &format!("{}.000 battery BAT0 {lvl}\n", i + 1);
+        }
+        let consumer = replay(&trace).await;
+        assert_eq!(
+            *consumer.bodies.lock().unwrap(),
+            ["Battery level is low at 30%"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn unchanged_statuses_are_not_observed() {
+        let consumer = replay(
+            "0.000 adapter connected
+            0.000 status BAT0 charging power=10
+            1.000 battery BAT0 50
+            1.000 status BAT0 charging power=12
+            2.000 adapter disconnected
+            2.000 status BAT0 discharging power=12",
+        )
+        .await;
+        assert_eq!(
+            *consumer.observed.lock().unwrap(),
+            [
+                "Connected (Connected)",
+                "BAT0 Charging (Connected)",
+                "BAT0 50 (Connected)",
+                "Disconnected (Disconnected)",
+                "BAT0 Discharging (Disconnected)",
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn simulated_discharge_notifies_thresholds() {
+        // 50% to 5% then charging, 75 simulated minutes
+        let bodies = simulate(Config::default(), Duration::from_secs(74)).await;
+        assert_eq!(
+            bodies,
+            [
+                "Battery level is low at 30%",
+                "Battery level is low at 15%",
+                "Battery level is critical at 8%",
+                "AC Adapter has been connected",
+            ]
+        );
+    }
+
+    /// Run `stream_loop` on the simulate backend for `duration`,
+    /// returning whether the critical action ran
+    /// the action is a command creating a file, run with `grace`
+    async fn critical_action_ran(mut config: Config, grace: Duration, duration: Duration) -> bool {
+        let dir = tempfile::tempdir().unwrap();
+        let ran = dir.path().join("ran");
+        let mut threshold = Threshold::new(lvl(5), EvPriority::High, "", "act");
+        threshold.action = Some(marker_action(&ran));
+        threshold.grace = Some(grace);
+        config.critical_action = Some(threshold);
+
+        let source = source(Backend::Simulate, Trace::default(), &config);
+        let stream = source.open().await.unwrap();
+        let monitor = monitor(&config);
+        let (_tx, config) = watch::channel(config);
+        let run = stream_loop(
+            &source,
+            stream,
+            config,
+            |_| MockConsumer::default(),
+            monitor,
+        );
+        time::timeout(duration, run).await.unwrap_err();
+
+        marker_written(&ran).await
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn critical_action_is_cancelled_by_the_adapter() {
+        let mut config = Config::default();
+        config.simulate.level = 10.0;
+        config.simulate.phases = vec![
+            Phase {
+                duration: Duration::from_secs(10 * 60),
+                adapter: AdapterStatus::Disconnected,
+                rate: -1.0,
+            },
+            Phase {
+                duration: Duration::from_secs(10 * 60),
+                adapter: AdapterStatus::Connected,
+                rate: 1.0,
+            },
+        ];
+        // 5% is reached after 5 simulated minutes (5s), and the adapter is connected
+        // 5 simulated minutes later: a 2s grace period runs the action, a 6s one doesn't
+        let duration = Duration::from_secs(19);
+        assert!(critical_action_ran(config.clone(), Duration::from_secs(2), duration).await);
+        assert!(!critical_action_ran(config, Duration::from_secs(6), duration).await);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn charged_is_notified_when_starting_on_ac() {
+        let sysfs = FakeSysfs::discharging();
+        sysfs.set("BAT0", "capacity", "90");
+        sysfs.set("BAT0", "status", "Charging");
+        sysfs.set("AC", "online", "1");
+        let config = Config {
+            charged: vec![Threshold::new(lvl(80), EvPriority::Low, "", "charged")],
+            ..Default::default()
+        };
+        let source = StreamSource {
+            sysfs_root: sysfs.root().to_owned(),
+            ..source(Backend::Polling, Trace::default(), &config)
+        };
+        let stream = source.open().await.unwrap();
+        let monitor = monitor(&config);
+        let consumer = MockConsumer::default();
+        let (_tx, config) = watch::channel(config);
+        let run = stream_loop(&source, stream, config, |_| consumer.clone(), monitor);
+        time::timeout(Duration::from_secs(1), run)
+            .await
+            .unwrap_err();
+        assert_eq!(*consumer.bodies.lock().unwrap(), ["charged"]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reminders_are_sent_while_reopening() {
+        let mut config = Config::default();
+        config.critical.remind_interval = Some(Duration::from_secs(60));
+        // Not a directory, a temporary error
+        let root = tempfile::NamedTempFile::new().unwrap();
+        let source = StreamSource {
+            sysfs_root: root.path().to_owned(),
+            ..source(Backend::Polling, Trace::default(), &config)
+        };
+        assert!(matches!(source.open().await, Err(e) if !e.is_permanent()));
+        let mut monitor = monitor(&config);
+        let adapter = BatEvent::Adapter(AdapterStatus::Disconnected);
+        monitor.accept(&adapter);
+        assert!(monitor.handle(adapter).is_none());
+        let event = BatEvent::Battery("BAT0".into(), lvl(8));
+        monitor.accept(&event);
+        assert!(monitor.handle(event).is_some());
+
+        // The stream fails right away, and can't be reopened
+        let failing = std::io::Error::other("gone");
+        let stream: BatStream = Box::pin(futures_lite::stream::once(Err(failing.into())));
+        let consumer = MockConsumer::default();
+        let (_tx, config) = watch::channel(config);
+        let run = stream_loop(&source, stream, config, |_| consumer.clone(), monitor);
+        time::timeout(Duration::from_secs(61), run)
+            .await
+            .unwrap_err();
+        assert_eq!(
+            *consumer.bodies.lock().unwrap(),
+            ["Battery level is critical at 8%"]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn permanent_errors_are_returned() {
+        let config = Config::default();
+        let source = StreamSource {
+            sysfs_root: PathBuf::from("/nonexistent"),
+            ..source(Backend::Polling, Trace::default(), &config)
+        };
+        let (_tx, config_rx) = watch::channel(config.clone());
+
+        // The device is gone, and so is its reopened stream
+        let failing = std::io::Error::other("gone");
+        let stream: BatStream = Box::pin(futures_lite::stream::once(Err(failing.into())));
+        let run = stream_loop(
+            &source,
+            stream,
+            config_rx.clone(),
+            |_| MockConsumer::default(),
+            monitor(&config),
+        );
+        let err = time::timeout(Duration::from_secs(10), run)
+            .await
+            .unwrap()
+            .unwrap_err();
+        assert!(err.is_permanent(), "{err}");
+
+        let missing = std::io::Error::from(std::io::ErrorKind::NotFound);
+        let stream: BatStream = Box::pin(futures_lite::stream::once(Err(missing.into())));
+        let start = time::Instant::now();
+        let run = stream_loop(
+            &source,
+            stream,
+            config_rx,
+            |_| MockConsumer::default(),
+            monitor(&config),
+        );
+        assert!(run.await.unwrap_err().is_permanent());
+        assert_eq!(start.elapsed(), Duration::ZERO);
+    }
+}